@@ -0,0 +1,457 @@
+use bytes::Bytes;
+use mini_redis::{Connection, Frame};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "\
+Usage: benchmark [-h host] [-p port] [-c clients] [-n requests] [-P pipeline]
+                 [-r keyspace] [-d datasize] [-t tests] [--csv]
+
+  -h <host>      server hostname (default 127.0.0.1)
+  -p <port>      server port (default 6379)
+  -c <clients>   number of parallel connections (default 50)
+  -n <requests>  total number of requests per test (default 100000)
+  -P <numreq>    pipeline <numreq> requests (default 1, no pipeline)
+  -r <keyspace>  use random keys in the range [0, keyspace) (default 10000)
+  -d <size>      value size in bytes for SET/LPUSH (default 3)
+  -t <tests>     comma separated list of tests: get,set,incr,lpush (default all)
+  --csv          output in CSV format
+
+Tests of commands the server doesn't know are skipped: spawning only has
+GET and SET, shared-state adds INCR, sharded has all four.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Test {
+    Get,
+    Set,
+    Incr,
+    Lpush,
+}
+
+impl Test {
+    fn parse(name: &str) -> Option<Test> {
+        match name.to_lowercase().as_str() {
+            "get" => Some(Test::Get),
+            "set" => Some(Test::Set),
+            "incr" => Some(Test::Incr),
+            "lpush" => Some(Test::Lpush),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Test::Get => "GET",
+            Test::Set => "SET",
+            Test::Incr => "INCR",
+            Test::Lpush => "LPUSH",
+        }
+    }
+
+    /// Key `n` of the test's keyspace. Counters and lists get keys of their
+    /// own, so INCR doesn't hit the values SET wrote.
+    fn key(&self, n: u64) -> String {
+        let prefix = match self {
+            Test::Get | Test::Set => "key",
+            Test::Incr => "counter",
+            Test::Lpush => "list",
+        };
+        format!("{}:{:012}", prefix, n)
+    }
+
+    fn frame(&self, key: String, value: &Bytes) -> Frame {
+        let mut args = vec![
+            Frame::Bulk(Bytes::from(self.name())),
+            Frame::Bulk(Bytes::from(key)),
+        ];
+        if let Test::Set | Test::Lpush = self {
+            args.push(Frame::Bulk(value.clone()));
+        }
+        Frame::Array(args)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    addr: String,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    keyspace: u64,
+    data_size: usize,
+    tests: Vec<Test>,
+    csv: bool,
+}
+
+impl Config {
+    fn new(args: &[String]) -> Result<Config, String> {
+        let mut host = "127.0.0.1".to_string();
+        let mut port = "6379".to_string();
+        let mut config = Config {
+            addr: String::new(),
+            clients: 50,
+            requests: 100_000,
+            pipeline: 1,
+            keyspace: 10_000,
+            data_size: 3,
+            tests: vec![Test::Set, Test::Get, Test::Incr, Test::Lpush],
+            csv: false,
+        };
+
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            if arg == "--csv" {
+                config.csv = true;
+                continue;
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "-h" => host = value.clone(),
+                "-p" => port = value.clone(),
+                "-c" => config.clients = parse_number(arg, value)?,
+                "-n" => config.requests = parse_number(arg, value)?,
+                "-P" => config.pipeline = parse_number(arg, value)?,
+                "-r" => config.keyspace = parse_number(arg, value)?,
+                "-d" => config.data_size = value.parse().map_err(|_| bad_value(arg, value))?,
+                "-t" => {
+                    config.tests = value
+                        .split(',')
+                        .map(|name| Test::parse(name).ok_or_else(|| bad_value(arg, name)))
+                        .collect::<Result<_, _>>()?
+                }
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        config.addr = format!("{}:{}", host, port);
+        Ok(config)
+    }
+}
+
+fn parse_number<T: std::str::FromStr + PartialOrd + From<u8>>(
+    arg: &str,
+    value: &str,
+) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(n) if n >= T::from(1) => Ok(n),
+        _ => Err(bad_value(arg, value)),
+    }
+}
+
+fn bad_value(arg: &str, value: &str) -> String {
+    format!("invalid value {:?} for {}", value, arg)
+}
+
+struct Report {
+    test: Test,
+    elapsed: Duration,
+    // Per request latency in microseconds, sorted ascending.
+    latencies: Vec<u64>,
+    errors: usize,
+    // Connections that failed before finishing their requests, and why the
+    // first one did.
+    failed: usize,
+    failure: Option<String>,
+}
+
+impl Report {
+    fn requests_per_second(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Latency in milliseconds below which `p` percent of the requests completed.
+    fn percentile(&self, p: f64) -> f64 {
+        percentile(&self.latencies, p) as f64 / 1000.0
+    }
+
+    fn print(&self, config: &Config) {
+        println!("====== {} ======", self.test.name());
+        println!(
+            "  {} requests completed in {:.2} seconds",
+            self.latencies.len(),
+            self.elapsed.as_secs_f64()
+        );
+        println!(
+            "  {} parallel clients, pipeline {}, {} bytes payload, keyspace {}",
+            config.clients, config.pipeline, config.data_size, config.keyspace
+        );
+        println!("  {:.2} requests per second", self.requests_per_second());
+        println!(
+            "  latency p50={:.3} ms p99={:.3} ms p99.9={:.3} ms",
+            self.percentile(50.0),
+            self.percentile(99.0),
+            self.percentile(99.9)
+        );
+        println!("  {} error replies", self.errors);
+        if let Some(failure) = &self.failure {
+            println!("  {} connections failed: {}", self.failed, failure);
+        }
+        println!();
+    }
+
+    fn print_csv(&self) {
+        println!(
+            "\"{}\",\"{:.2}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{}\",\"{}\"",
+            self.test.name(),
+            self.requests_per_second(),
+            self.percentile(50.0),
+            self.percentile(99.0),
+            self.percentile(99.9),
+            self.errors,
+            self.failed
+        );
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (sorted.len() as f64 * p / 100.0).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Issue `requests` commands over a single connection, `pipeline` at a time,
+/// and return the latency of each one along with the number of error replies.
+async fn client(
+    config: Arc<Config>,
+    test: Test,
+    requests: usize,
+) -> mini_redis::Result<(Vec<u64>, usize)> {
+    let socket = TcpStream::connect(&config.addr).await?;
+    socket.set_nodelay(true)?;
+    let mut connection = Connection::new(socket);
+    let mut rng = StdRng::from_entropy();
+    let value = Bytes::from(vec![b'x'; config.data_size]);
+
+    let mut latencies = Vec::with_capacity(requests);
+    let mut errors = 0;
+    let mut remaining = requests;
+    while remaining > 0 {
+        let batch = remaining.min(config.pipeline);
+        let start = Instant::now();
+        for _ in 0..batch {
+            let key = test.key(rng.gen_range(0..config.keyspace));
+            connection.write_frame(&test.frame(key, &value)).await?;
+        }
+        for _ in 0..batch {
+            match connection.read_frame().await? {
+                Some(Frame::Error(_)) => errors += 1,
+                Some(_) => {}
+                None => return Err("connection reset by server".into()),
+            }
+            latencies.push(start.elapsed().as_micros() as u64);
+        }
+        remaining -= batch;
+    }
+    Ok((latencies, errors))
+}
+
+/// Whether the server knows the command of `test`, trying it once. Servers
+/// reply with an error to commands they don't know, or hang up.
+async fn supported(config: &Config, test: Test) -> mini_redis::Result<bool> {
+    let mut connection = Connection::new(TcpStream::connect(&config.addr).await?);
+    let value = Bytes::from(vec![b'x'; config.data_size]);
+    connection
+        .write_frame(&test.frame(test.key(0), &value))
+        .await?;
+    Ok(match connection.read_frame().await {
+        Ok(Some(Frame::Error(e))) => !e.starts_with("ERR unknown command"),
+        Ok(Some(_)) => true,
+        Ok(None) | Err(_) => false,
+    })
+}
+
+/// Run `test` on `config.clients` connections. A connection that fails is
+/// counted and the others carry on.
+async fn bench(config: Arc<Config>, test: Test) -> Report {
+    let start = Instant::now();
+    let mut handles = Vec::with_capacity(config.clients);
+    for i in 0..config.clients {
+        let mut requests = config.requests / config.clients;
+        if i < config.requests % config.clients {
+            requests += 1;
+        }
+        handles.push(tokio::spawn(client(config.clone(), test, requests)));
+    }
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut errors = 0;
+    let mut failed = 0;
+    let mut failure = None;
+    for handle in handles {
+        let result = match handle.await {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok((l, e)) => {
+                latencies.extend(l);
+                errors += e;
+            }
+            Err(e) => {
+                failed += 1;
+                failure.get_or_insert_with(|| e.to_string());
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    Report {
+        test,
+        elapsed,
+        latencies,
+        errors,
+        failed,
+        failure,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    let config = match Config::new(&args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    if config.csv {
+        println!(
+            "\"test\",\"rps\",\"p50_latency_ms\",\"p99_latency_ms\",\"p999_latency_ms\",\"errors\",\"failed_connections\""
+        );
+    }
+    for &test in &config.tests {
+        match supported(&config, test).await {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("{}: not supported by the server, skipped", test.name());
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", test.name(), e);
+                continue;
+            }
+        }
+        let report = bench(config.clone(), test).await;
+        if config.csv {
+            report.print_csv();
+        } else {
+            report.print(&config);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn config() {
+        let config = Config::new(&args("benchmark -p 7000 -c 4 -P 16 -t get,incr --csv")).unwrap();
+        assert_eq!("127.0.0.1:7000", config.addr);
+        assert_eq!(4, config.clients);
+        assert_eq!(16, config.pipeline);
+        assert_eq!(vec![Test::Get, Test::Incr], config.tests);
+        assert!(config.csv);
+
+        assert!(Config::new(&args("benchmark -c 0")).is_err());
+        assert!(Config::new(&args("benchmark -t get,del")).is_err());
+        assert!(Config::new(&args("benchmark -n")).is_err());
+    }
+
+    /// The shared-state server, built and started on a free port, killed
+    /// when dropped.
+    struct Server(std::process::Child, u16);
+
+    impl Server {
+        fn start() -> Server {
+            let status = std::process::Command::new(env!("CARGO"))
+                .args(["build", "-q", "-p", "shared-state"])
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let exe = env::current_exe().unwrap();
+            let path = exe
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .join(format!("shared-state{}", env::consts::EXE_SUFFIX));
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let child = std::process::Command::new(path)
+                .args(["--port", &port.to_string()])
+                .spawn()
+                .unwrap();
+            let started = Instant::now();
+            while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+                assert!(
+                    started.elapsed() < Duration::from_secs(10),
+                    "server didn't start"
+                );
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Server(child, port)
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_state_server() {
+        let server = Server::start();
+        let config = Arc::new(
+            Config::new(&args(&format!(
+                "benchmark -p {} -c 4 -n 203 -P 8 -r 50 -d 16",
+                server.1
+            )))
+            .unwrap(),
+        );
+        for &test in &[Test::Set, Test::Get, Test::Incr] {
+            assert!(supported(&config, test).await.unwrap(), "{:?}", test);
+            let report = bench(config.clone(), test).await;
+            assert_eq!(203, report.latencies.len());
+            assert_eq!((0, 0, None), (report.errors, report.failed, report.failure));
+            assert!(report.latencies.windows(2).all(|w| w[0] <= w[1]));
+        }
+        assert!(!supported(&config, Test::Lpush).await.unwrap());
+
+        // Connections that can't reach the server are counted, not fatal.
+        drop(server);
+        let report = bench(config.clone(), Test::Get).await;
+        assert!(report.latencies.is_empty());
+        assert_eq!(4, report.failed);
+        assert!(report.failure.is_some());
+    }
+
+    #[test]
+    fn percentiles() {
+        let sorted = (1..=1000).collect::<Vec<u64>>();
+        assert_eq!(500, percentile(&sorted, 50.0));
+        assert_eq!(990, percentile(&sorted, 99.0));
+        assert_eq!(999, percentile(&sorted, 99.9));
+        assert_eq!(1, percentile(&sorted, 0.0));
+        assert_eq!(0, percentile(&[], 50.0));
+    }
+}
@@ -0,0 +1,259 @@
+//! The shared-state server with its store split into shards, each behind its
+//! own lock, so connections working on different keys rarely wait for each
+//! other. It speaks just the commands the benchmark issues: GET, SET, INCR
+//! and LPUSH.
+//!
+//! mini-redis frames only carry unsigned integers, so INCR counts up from
+//! zero and rejects negative values.
+
+use bytes::Bytes;
+use mini_redis::{Connection, Frame};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+
+const USAGE: &str = "Usage: sharded [--port <port>] [--shards <count>]";
+
+const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+}
+
+type Shard = Mutex<HashMap<String, Value>>;
+
+struct Db {
+    shards: Vec<Shard>,
+}
+
+impl Db {
+    fn new(shards: usize) -> Db {
+        Db {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Run a command, locking only the shard of its key.
+    fn execute(&self, args: &[Bytes]) -> Frame {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        // Every known command takes a key, so check the name before counting
+        // arguments.
+        let arity = match name.as_str() {
+            "GET" | "INCR" => args.len() == 2,
+            "SET" => args.len() == 3,
+            "LPUSH" => args.len() >= 3,
+            _ => return Frame::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        };
+        if !arity {
+            return wrong_arity(&name);
+        }
+        let key = String::from_utf8_lossy(&args[1]).into_owned();
+        let mut shard = self.shard(&key).lock().unwrap();
+        match name.as_str() {
+            "GET" => match shard.get(&key) {
+                Some(Value::String(value)) => Frame::Bulk(value.clone()),
+                Some(Value::List(_)) => Frame::Error(WRONG_TYPE.to_string()),
+                None => Frame::Null,
+            },
+            "SET" => {
+                shard.insert(key, Value::String(args[2].clone()));
+                Frame::Simple("OK".to_string())
+            }
+            "INCR" => {
+                let n = match shard.get(&key) {
+                    Some(Value::String(value)) => match std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse::<u64>().ok())
+                    {
+                        Some(n) => n,
+                        None => {
+                            return Frame::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            )
+                        }
+                    },
+                    Some(Value::List(_)) => return Frame::Error(WRONG_TYPE.to_string()),
+                    None => 0,
+                };
+                let n = match n.checked_add(1) {
+                    Some(n) => n,
+                    None => {
+                        return Frame::Error(
+                            "ERR increment or decrement would overflow".to_string(),
+                        )
+                    }
+                };
+                shard.insert(key, Value::String(Bytes::from(n.to_string())));
+                Frame::Integer(n)
+            }
+            "LPUSH" => {
+                let list = match shard
+                    .entry(key)
+                    .or_insert_with(|| Value::List(VecDeque::new()))
+                {
+                    Value::List(list) => list,
+                    Value::String(_) => return Frame::Error(WRONG_TYPE.to_string()),
+                };
+                for value in &args[2..] {
+                    list.push_front(value.clone());
+                }
+                Frame::Integer(list.len() as u64)
+            }
+            _ => unreachable!("unknown commands are answered above"),
+        }
+    }
+}
+
+fn wrong_arity(name: &str) -> Frame {
+    Frame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+/// Split a command frame into its name and arguments.
+fn parse(frame: Frame) -> Result<Vec<Bytes>, String> {
+    let items = match frame {
+        Frame::Array(items) if !items.is_empty() => items,
+        frame => {
+            return Err(format!(
+                "ERR protocol error; expected array, got {:?}",
+                frame
+            ))
+        }
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            Frame::Bulk(data) => Ok(data),
+            Frame::Simple(s) => Ok(Bytes::from(s)),
+            Frame::Integer(n) => Ok(Bytes::from(n.to_string())),
+            frame => Err(format!("ERR protocol error; unexpected {:?}", frame)),
+        })
+        .collect()
+}
+
+#[tokio::main]
+async fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    let (mut port, mut shards) = (6379u16, 16usize);
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let parsed = match (arg.as_str(), iter.next()) {
+            ("--port", Some(value)) => value.parse().map(|value| port = value).is_ok(),
+            ("--shards", Some(value)) => match value.parse() {
+                Ok(value) if value > 0 => {
+                    shards = value;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !parsed {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    serve(listener, Arc::new(Db::new(shards))).await;
+}
+
+async fn serve(listener: TcpListener, db: Arc<Db>) {
+    loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        socket.set_nodelay(true).unwrap();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process(socket, db).await {
+                println!("connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn process(socket: TcpStream, db: Arc<Db>) -> mini_redis::Result<()> {
+    let mut connection = Connection::new(socket);
+    while let Some(frame) = connection.read_frame().await? {
+        let response = match parse(frame) {
+            Ok(args) => db.execute(&args),
+            Err(e) => Frame::Error(e),
+        };
+        connection.write_frame(&response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(db: &Db, args: &[&str]) -> Frame {
+        let args = args
+            .iter()
+            .map(|arg| Bytes::from(arg.to_string()))
+            .collect::<Vec<_>>();
+        db.execute(&args)
+    }
+
+    fn error(frame: Frame) -> String {
+        match frame {
+            Frame::Error(e) => e,
+            frame => panic!("expected an error, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let db = Db::new(4);
+        assert!(matches!(call(&db, &["GET", "k"]), Frame::Null));
+        assert!(matches!(call(&db, &["set", "k", "v"]), Frame::Simple(_)));
+        assert!(matches!(call(&db, &["GET", "k"]), Frame::Bulk(v) if v == "v"));
+
+        assert!(matches!(call(&db, &["INCR", "n"]), Frame::Integer(1)));
+        assert!(matches!(call(&db, &["INCR", "n"]), Frame::Integer(2)));
+        assert!(matches!(call(&db, &["GET", "n"]), Frame::Bulk(v) if v == "2"));
+        assert!(error(call(&db, &["INCR", "k"])).starts_with("ERR value is not an integer"));
+        call(&db, &["SET", "max", &u64::MAX.to_string()]);
+        assert!(error(call(&db, &["INCR", "max"])).contains("overflow"));
+
+        assert!(matches!(
+            call(&db, &["LPUSH", "l", "a", "b"]),
+            Frame::Integer(2)
+        ));
+        assert!(matches!(call(&db, &["LPUSH", "l", "c"]), Frame::Integer(3)));
+        assert!(error(call(&db, &["GET", "l"])).starts_with("WRONGTYPE"));
+        assert!(error(call(&db, &["LPUSH", "k", "x"])).starts_with("WRONGTYPE"));
+        assert!(matches!(call(&db, &["SET", "l", "v"]), Frame::Simple(_)));
+
+        assert!(error(call(&db, &["LPUSH", "l"])).contains("wrong number of arguments"));
+        assert!(error(call(&db, &["GET"])).contains("wrong number of arguments"));
+        assert!(error(call(&db, &["DEL", "k"])).starts_with("ERR unknown command"));
+        assert_eq!("ERR unknown command 'ping'", error(call(&db, &["PING"])));
+    }
+
+    #[test]
+    fn keys_spread_over_shards() {
+        let db = Db::new(8);
+        for i in 0..100 {
+            call(&db, &["SET", &format!("key:{}", i), "v"]);
+        }
+        let sizes = db
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(100, sizes.iter().sum::<usize>());
+        assert!(sizes.iter().all(|&size| size > 0), "{:?}", sizes);
+    }
+}
@@ -0,0 +1,363 @@
+//! Bit operations on string values. Bit 0 is the most significant bit of the
+//! first byte, as in Redis, and strings grow with zero bytes as needed.
+
+use crate::cmd::{self, int_arg};
+use crate::db::State;
+use crate::frame::Frame;
+use bytes::{Bytes, BytesMut};
+
+/// Largest offset accepted by SETBIT, the same 512MB limit Redis has.
+const MAX_OFFSET: u64 = 8 * 512 * 1024 * 1024 - 1;
+
+fn bit(data: &[u8], offset: u64) -> u8 {
+    match data.get((offset / 8) as usize) {
+        Some(byte) => (byte >> (7 - offset % 8)) & 1,
+        None => 0,
+    }
+}
+
+fn syntax_error() -> Frame {
+    Frame::Error("ERR syntax error".to_string())
+}
+
+fn not_an_integer() -> Frame {
+    Frame::Error("ERR value is not an integer or out of range".to_string())
+}
+
+fn parse_offset(arg: &Bytes) -> Result<u64, Frame> {
+    match int_arg(arg) {
+        Some(offset) if offset >= 0 && offset as u64 <= MAX_OFFSET => Ok(offset as u64),
+        _ => Err(Frame::Error(
+            "ERR bit offset is not an integer or out of range".to_string(),
+        )),
+    }
+}
+
+fn parse_bit(arg: &Bytes) -> Result<u8, Frame> {
+    match &arg[..] {
+        b"0" => Ok(0),
+        b"1" => Ok(1),
+        _ => Err(Frame::Error(
+            "ERR bit is not an integer or out of range".to_string(),
+        )),
+    }
+}
+
+/// Resolve an optional `start end [BYTE|BIT]` range into an inclusive range
+/// of bit offsets. Negative indexes count from the end, as with GETRANGE.
+fn bit_range(len: u64, args: &[Bytes]) -> Result<Option<(u64, u64)>, Frame> {
+    let (start, end, unit) = match args {
+        [] => {
+            return Ok(if len == 0 {
+                None
+            } else {
+                Some((0, len * 8 - 1))
+            })
+        }
+        [start] => (start, None, 8),
+        [start, end] => (start, Some(end), 8),
+        [start, end, unit] => match &unit.to_ascii_uppercase()[..] {
+            b"BYTE" => (start, Some(end), 8),
+            b"BIT" => (start, Some(end), 1),
+            _ => return Err(syntax_error()),
+        },
+        _ => return Err(syntax_error()),
+    };
+    let total = (len * 8 / unit) as i64;
+    let mut start = int_arg(start).ok_or_else(not_an_integer)?;
+    let mut end = match end {
+        Some(end) => int_arg(end).ok_or_else(not_an_integer)?,
+        None => total - 1,
+    };
+    if start < 0 {
+        start += total;
+    }
+    if end < 0 {
+        end += total;
+    }
+    start = start.max(0);
+    end = end.min(total - 1);
+    if total == 0 || start > end {
+        return Ok(None);
+    }
+    let unit = unit as i64;
+    Ok(Some((
+        (start * unit) as u64,
+        (end * unit + unit - 1) as u64,
+    )))
+}
+
+/// `SETBIT key offset value`
+pub fn setbit(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() != 4 {
+        return cmd::wrong_arity("setbit");
+    }
+    let (offset, value) = match (parse_offset(&args[2]), parse_bit(&args[3])) {
+        (Ok(offset), Ok(value)) => (offset, value),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+    let key = cmd::key(&args[1]);
+    // Change the value in place, it is only copied while a reply still holds
+    // on to it.
+    let mut data = match state.entries.remove(&key) {
+        Some(value) => value
+            .try_into_mut()
+            .unwrap_or_else(|shared| BytesMut::from(&shared[..])),
+        None => BytesMut::new(),
+    };
+    let index = (offset / 8) as usize;
+    if data.len() <= index {
+        data.resize(index + 1, 0);
+    }
+    let old = bit(&data, offset);
+    let mask = 1 << (7 - offset % 8);
+    if value == 1 {
+        data[index] |= mask;
+    } else {
+        data[index] &= !mask;
+    }
+    state.entries.insert(key, data.freeze());
+    Frame::Integer(old as i64)
+}
+
+/// `GETBIT key offset`
+pub fn getbit(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() != 3 {
+        return cmd::wrong_arity("getbit");
+    }
+    let offset = match parse_offset(&args[2]) {
+        Ok(offset) => offset,
+        Err(e) => return e,
+    };
+    match state.entries.get(&cmd::key(&args[1])) {
+        Some(data) => Frame::Integer(bit(data, offset) as i64),
+        None => Frame::Integer(0),
+    }
+}
+
+/// `BITCOUNT key [start end [BYTE|BIT]]`
+pub fn bitcount(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 2 || args.len() == 3 || args.len() > 5 {
+        return cmd::wrong_arity("bitcount");
+    }
+    let empty = Bytes::new();
+    let data = state.entries.get(&cmd::key(&args[1])).unwrap_or(&empty);
+    let (start, end) = match bit_range(data.len() as u64, &args[2..]) {
+        Ok(Some(range)) => range,
+        Ok(None) => return Frame::Integer(0),
+        Err(e) => return e,
+    };
+    let mut count = 0;
+    let mut offset = start;
+    while offset <= end {
+        if offset % 8 == 0 && offset + 7 <= end {
+            count += data[(offset / 8) as usize].count_ones() as i64;
+            offset += 8;
+        } else {
+            count += bit(data, offset) as i64;
+            offset += 1;
+        }
+    }
+    Frame::Integer(count)
+}
+
+/// `BITPOS key bit [start [end [BYTE|BIT]]]`
+pub fn bitpos(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 3 || args.len() > 6 {
+        return cmd::wrong_arity("bitpos");
+    }
+    let wanted = match parse_bit(&args[2]) {
+        Ok(bit) => bit,
+        Err(e) => return e,
+    };
+    let data = match state.entries.get(&cmd::key(&args[1])) {
+        Some(data) => data,
+        // A missing key is an infinite string of zeros.
+        None => return Frame::Integer(if wanted == 0 { 0 } else { -1 }),
+    };
+    let (start, end) = match bit_range(data.len() as u64, &args[3..]) {
+        Ok(Some(range)) => range,
+        Ok(None) => return Frame::Integer(-1),
+        Err(e) => return e,
+    };
+    let skip = if wanted == 1 { 0x00 } else { 0xff };
+    let mut offset = start;
+    while offset <= end {
+        if offset % 8 == 0 && offset + 7 <= end && data[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if bit(data, offset) == wanted {
+            return Frame::Integer(offset as i64);
+        }
+        offset += 1;
+    }
+    // Looking for a clear bit without an explicit end considers the string
+    // padded with zeros on the right.
+    if wanted == 0 && args.len() <= 4 {
+        return Frame::Integer(data.len() as i64 * 8);
+    }
+    Frame::Integer(-1)
+}
+
+/// `BITOP AND|OR|XOR|NOT destkey key [key ...]`
+pub fn bitop(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 4 {
+        return cmd::wrong_arity("bitop");
+    }
+    let op = String::from_utf8_lossy(&args[1]).to_uppercase();
+    if !["AND", "OR", "XOR", "NOT"].contains(&op.as_str()) {
+        return syntax_error();
+    }
+    if op == "NOT" && args.len() != 4 {
+        return Frame::Error("ERR BITOP NOT must be called with a single source key.".to_string());
+    }
+    let sources = args[3..]
+        .iter()
+        .map(|key| {
+            state
+                .entries
+                .get(&cmd::key(key))
+                .cloned()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<Bytes>>();
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let byte = |source: &Bytes, i: usize| source.get(i).copied().unwrap_or(0);
+
+    let mut result = vec![0u8; len];
+    for (i, out) in result.iter_mut().enumerate() {
+        let mut bytes = sources.iter().map(|s| byte(s, i));
+        *out = match op.as_str() {
+            "AND" => bytes.fold(0xff, |acc, b| acc & b),
+            "OR" => bytes.fold(0, |acc, b| acc | b),
+            "XOR" => bytes.fold(0, |acc, b| acc ^ b),
+            _ => !bytes.next().unwrap_or(0),
+        };
+    }
+
+    let dest = cmd::key(&args[2]);
+    if result.is_empty() {
+        state.entries.remove(&dest);
+    } else {
+        state.entries.insert(dest, Bytes::from(result));
+    }
+    Frame::Integer(len as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(state: &mut State, line: &str) -> Frame {
+        let args = line
+            .split_whitespace()
+            .map(|arg| Bytes::copy_from_slice(arg.as_bytes()))
+            .collect::<Vec<Bytes>>();
+        cmd::execute(state, &args)
+    }
+
+    #[test]
+    fn set_get_count() {
+        let mut state = State::default();
+        assert!(matches!(run(&mut state, "SETBIT users 7"), Frame::Error(_)));
+        assert_eq!(Frame::Integer(0), run(&mut state, "SETBIT users 7 1"));
+        assert_eq!(Frame::Integer(1), run(&mut state, "SETBIT users 7 0"));
+        run(&mut state, "SETBIT users 7 1");
+        run(&mut state, "SETBIT users 100 1");
+        assert_eq!(Frame::Integer(1), run(&mut state, "GETBIT users 100"));
+        assert_eq!(Frame::Integer(0), run(&mut state, "GETBIT users 99999"));
+        assert_eq!(13, state.entries["users"].len());
+        assert_eq!(Frame::Integer(2), run(&mut state, "BITCOUNT users"));
+        assert_eq!(Frame::Integer(1), run(&mut state, "BITCOUNT users 1 -1"));
+        assert_eq!(Frame::Integer(1), run(&mut state, "BITCOUNT users 0 7 BIT"));
+        assert_eq!(
+            Frame::Integer(0),
+            run(&mut state, "BITCOUNT users 8 99 BIT")
+        );
+        assert!(matches!(
+            run(&mut state, "SETBIT users -1 1"),
+            Frame::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut state, "SETBIT users 1 2"),
+            Frame::Error(_)
+        ));
+
+        // Bitmaps are plain strings, GET and SET see the same bytes.
+        run(&mut state, "SET foobar foobar");
+        assert_eq!(Frame::Integer(26), run(&mut state, "BITCOUNT foobar"));
+        assert_eq!(Frame::Integer(4), run(&mut state, "BITCOUNT foobar 0 0"));
+        assert_eq!(Frame::Integer(6), run(&mut state, "BITCOUNT foobar 1 1"));
+        assert_eq!(
+            Frame::Integer(17),
+            run(&mut state, "BITCOUNT foobar 5 30 BIT")
+        );
+    }
+
+    #[test]
+    fn set_in_place() {
+        let mut state = State::default();
+        run(&mut state, "SETBIT users 80000 1");
+        let at = state.entries["users"].as_ptr();
+        run(&mut state, "SETBIT users 5 1");
+        run(&mut state, "SETBIT users 80000 0");
+        assert_eq!(at, state.entries["users"].as_ptr());
+        assert_eq!(Frame::Integer(1), run(&mut state, "BITCOUNT users"));
+
+        // A value still being sent is left alone.
+        let reply = state.entries["users"].clone();
+        run(&mut state, "SETBIT users 6 1");
+        assert_eq!(0b0000_0100, reply[0]);
+        assert_eq!(0b0000_0110, state.entries["users"][0]);
+    }
+
+    #[test]
+    fn pos() {
+        let mut state = State::default();
+        state
+            .entries
+            .insert("a".into(), Bytes::from_static(&[0xff, 0xf0, 0x00]));
+        assert_eq!(Frame::Integer(12), run(&mut state, "BITPOS a 0"));
+        assert_eq!(Frame::Integer(0), run(&mut state, "BITPOS a 1"));
+        assert_eq!(Frame::Integer(8), run(&mut state, "BITPOS a 1 1"));
+        assert_eq!(Frame::Integer(-1), run(&mut state, "BITPOS a 1 2"));
+        assert_eq!(Frame::Integer(9), run(&mut state, "BITPOS a 1 9 -1 BIT"));
+        state
+            .entries
+            .insert("b".into(), Bytes::from_static(&[0xff]));
+        assert_eq!(Frame::Integer(8), run(&mut state, "BITPOS b 0"));
+        assert_eq!(Frame::Integer(-1), run(&mut state, "BITPOS b 0 0 -1"));
+        assert_eq!(Frame::Integer(0), run(&mut state, "BITPOS missing 0"));
+        assert_eq!(Frame::Integer(-1), run(&mut state, "BITPOS missing 1"));
+    }
+
+    #[test]
+    fn op() {
+        let mut state = State::default();
+        state
+            .entries
+            .insert("a".into(), Bytes::from_static(&[0b1100, 0xff]));
+        state
+            .entries
+            .insert("b".into(), Bytes::from_static(&[0b1010]));
+        assert_eq!(Frame::Integer(2), run(&mut state, "BITOP AND dest a b"));
+        assert_eq!(&[0b1000, 0][..], &state.entries["dest"][..]);
+        run(&mut state, "BITOP OR dest a b");
+        assert_eq!(&[0b1110, 0xff][..], &state.entries["dest"][..]);
+        run(&mut state, "BITOP XOR dest a b");
+        assert_eq!(&[0b0110, 0xff][..], &state.entries["dest"][..]);
+        run(&mut state, "BITOP NOT dest b");
+        assert_eq!(&[0xf5][..], &state.entries["dest"][..]);
+        assert!(matches!(
+            run(&mut state, "BITOP NOT dest a b"),
+            Frame::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut state, "BITOP NAND dest a b"),
+            Frame::Error(_)
+        ));
+        assert_eq!(Frame::Integer(0), run(&mut state, "BITOP OR dest missing"));
+        assert!(!state.entries.contains_key("dest"));
+    }
+}
@@ -0,0 +1,448 @@
+//! Cluster mode: the keyspace is split into 16384 hash slots, each owned by
+//! one node. Commands for keys in a slot owned elsewhere are answered with a
+//! `MOVED` redirection, and slots being migrated use `ASK` redirections until
+//! `CLUSTER SETSLOT <slot> NODE <id>` hands them over.
+
+use crate::cmd;
+use crate::connection::Connection;
+use crate::db::{Db, State};
+use crate::frame::Frame;
+use crate::scripting::Scripts;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+
+pub const SLOTS: usize = 16384;
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster uses for key slots.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Slot of `key`. If the key contains a non-empty `{...}` hash tag only the
+/// tag is hashed, so related keys can be kept on the same node.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let mut hashed = key;
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if len > 0 {
+                hashed = &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    crc16(hashed) % SLOTS as u16
+}
+
+/// Node ids are derived from the address, so every node computes the same
+/// ids from the same `--cluster` list.
+fn node_id(addr: &str) -> String {
+    let fnv = |seed: u64| {
+        addr.bytes().fold(0xcbf29ce484222325 ^ seed, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+    };
+    format!("{:016x}{:016x}{:08x}", fnv(1), fnv(2), fnv(3) as u32)
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: String,
+    pub addr: String,
+}
+
+pub struct Cluster {
+    myself: usize,
+    nodes: Vec<Node>,
+    // Index into `nodes` of the owner of every slot.
+    slots: Vec<usize>,
+    migrating: HashMap<u16, usize>,
+    importing: HashMap<u16, usize>,
+}
+
+impl Cluster {
+    /// Split the slots evenly between `addrs`, in order. `myself` must be one
+    /// of them.
+    pub fn new(addrs: &[String], myself: &str) -> Result<Cluster, String> {
+        let myself = addrs
+            .iter()
+            .position(|addr| addr == myself)
+            .ok_or_else(|| format!("{} is not part of the cluster {:?}", myself, addrs))?;
+        let nodes = addrs
+            .iter()
+            .map(|addr| Node {
+                id: node_id(addr),
+                addr: addr.clone(),
+            })
+            .collect::<Vec<Node>>();
+        let slots = (0..SLOTS).map(|slot| slot * nodes.len() / SLOTS).collect();
+        Ok(Cluster {
+            myself,
+            nodes,
+            slots,
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        })
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[self.myself]
+    }
+
+    fn node(&self, id: &[u8]) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id.as_bytes() == id)
+    }
+
+    /// Contiguous runs of slots with the same owner, as `(start, end, node)`.
+    fn ranges(&self) -> Vec<(usize, usize, usize)> {
+        let mut ranges: Vec<(usize, usize, usize)> = Vec::new();
+        for (slot, &node) in self.slots.iter().enumerate() {
+            match ranges.last_mut() {
+                Some(last) if last.2 == node => last.1 = slot,
+                _ => ranges.push((slot, slot, node)),
+            }
+        }
+        ranges
+    }
+
+    /// The redirection to send instead of running a command on `keys`, if
+    /// any. `asking` is set when the client sent `ASKING` just before.
+    fn redirect(&self, state: &State, keys: &[Bytes], asking: bool) -> Option<Frame> {
+        let slot = key_slot(keys.first()?);
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Some(Frame::Error(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+            ));
+        }
+
+        let owner = self.slots[slot as usize];
+        if owner == self.myself {
+            if let Some(&target) = self.migrating.get(&slot) {
+                // Keys that were already moved are looked up on the target.
+                let missing = keys
+                    .iter()
+                    .any(|key| !state.entries.contains_key(&*String::from_utf8_lossy(key)));
+                if missing {
+                    return Some(self.error("ASK", slot, target));
+                }
+            }
+            None
+        } else if asking && self.importing.contains_key(&slot) {
+            None
+        } else {
+            Some(self.error("MOVED", slot, owner))
+        }
+    }
+
+    fn error(&self, kind: &str, slot: u16, node: usize) -> Frame {
+        Frame::Error(format!("{} {} {}", kind, slot, self.nodes[node].addr))
+    }
+
+    fn nodes_info(&self) -> String {
+        let mut out = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let port = node.addr.rsplit(':').next().unwrap_or("0");
+            let cport = port.parse::<u32>().map(|p| p + 10000).unwrap_or(0);
+            let flags = if i == self.myself {
+                "myself,master"
+            } else {
+                "master"
+            };
+            out.push_str(&format!(
+                "{} {}@{} {} - 0 0 0 connected",
+                node.id, node.addr, cport, flags
+            ));
+            for (start, end, _) in self.ranges().into_iter().filter(|r| r.2 == i) {
+                if start == end {
+                    out.push_str(&format!(" {}", start));
+                } else {
+                    out.push_str(&format!(" {}-{}", start, end));
+                }
+            }
+            if i == self.myself {
+                let mut migrating = self.migrating.iter().collect::<Vec<_>>();
+                migrating.sort();
+                for (slot, &target) in migrating {
+                    out.push_str(&format!(" [{}->-{}]", slot, self.nodes[target].id));
+                }
+                let mut importing = self.importing.iter().collect::<Vec<_>>();
+                importing.sort();
+                for (slot, &source) in importing {
+                    out.push_str(&format!(" [{}-<-{}]", slot, self.nodes[source].id));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn set_slot(&mut self, args: &[Bytes]) -> Frame {
+        let slot = match parse_slot(&args[2]) {
+            Ok(slot) => slot,
+            Err(e) => return e,
+        };
+        let subcommand = String::from_utf8_lossy(&args[3]).to_uppercase();
+        if subcommand == "STABLE" && args.len() == 4 {
+            self.migrating.remove(&slot);
+            self.importing.remove(&slot);
+            return ok();
+        }
+        if args.len() != 5 {
+            return cmd::wrong_arity("cluster|setslot");
+        }
+        let node = match self.node(&args[4]) {
+            Some(node) => node,
+            None => {
+                return Frame::Error(format!(
+                    "ERR I don't know about node {}",
+                    String::from_utf8_lossy(&args[4])
+                ))
+            }
+        };
+        let owner = self.slots[slot as usize];
+        match subcommand.as_str() {
+            "MIGRATING" if owner != self.myself => {
+                Frame::Error(format!("ERR I'm not the owner of hash slot {}", slot))
+            }
+            "MIGRATING" => {
+                self.migrating.insert(slot, node);
+                ok()
+            }
+            "IMPORTING" if owner == self.myself => {
+                Frame::Error(format!("ERR I'm already the owner of hash slot {}", slot))
+            }
+            "IMPORTING" => {
+                self.importing.insert(slot, node);
+                ok()
+            }
+            "NODE" => {
+                self.slots[slot as usize] = node;
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                ok()
+            }
+            _ => Frame::Error(
+                "ERR Invalid CLUSTER SETSLOT action or number of arguments".to_string(),
+            ),
+        }
+    }
+}
+
+fn ok() -> Frame {
+    Frame::Simple("OK".to_string())
+}
+
+fn parse_slot(arg: &Bytes) -> Result<u16, Frame> {
+    match String::from_utf8_lossy(arg).parse::<u16>() {
+        Ok(slot) if (slot as usize) < SLOTS => Ok(slot),
+        _ => Err(Frame::Error("ERR Invalid or out of range slot".to_string())),
+    }
+}
+
+/// See `Cluster::redirect`. Always `None` when cluster mode is disabled.
+pub fn redirect(state: &State, args: &[Bytes], asking: bool) -> Option<Frame> {
+    state
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.redirect(state, cmd::keys(args), asking))
+}
+
+/// `CLUSTER <subcommand> [args...]`
+pub fn command(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 2 {
+        return cmd::wrong_arity("cluster");
+    }
+    let subcommand = String::from_utf8_lossy(&args[1]).to_uppercase();
+    if subcommand == "KEYSLOT" {
+        return match args.len() {
+            3 => Frame::Integer(key_slot(&args[2]) as i64),
+            _ => cmd::wrong_arity("cluster|keyslot"),
+        };
+    }
+
+    let cluster = match state.cluster.as_mut() {
+        Some(cluster) => cluster,
+        None => return Frame::Error("ERR This instance has cluster support disabled".to_string()),
+    };
+    match (subcommand.as_str(), args.len()) {
+        ("MYID", 2) => Frame::bulk(&cluster.myself().id),
+        ("NODES", 2) => Frame::bulk(&cluster.nodes_info()),
+        ("SLOTS", 2) => Frame::Array(
+            cluster
+                .ranges()
+                .into_iter()
+                .map(|(start, end, node)| {
+                    let node = &cluster.nodes[node];
+                    let (host, port) = node.addr.rsplit_once(':').unwrap_or((&node.addr, "0"));
+                    Frame::Array(vec![
+                        Frame::Integer(start as i64),
+                        Frame::Integer(end as i64),
+                        Frame::Array(vec![
+                            Frame::bulk(host),
+                            Frame::Integer(port.parse().unwrap_or(0)),
+                            Frame::bulk(&node.id),
+                        ]),
+                    ])
+                })
+                .collect(),
+        ),
+        ("SETSLOT", n) if n >= 4 => cluster.set_slot(args),
+        ("COUNTKEYSINSLOT", 3) | ("GETKEYSINSLOT", 4) => {
+            let slot = match parse_slot(&args[2]) {
+                Ok(slot) => slot,
+                Err(e) => return e,
+            };
+            let mut keys = state
+                .entries
+                .keys()
+                .filter(|key| key_slot(key.as_bytes()) == slot)
+                .collect::<Vec<&String>>();
+            if subcommand == "COUNTKEYSINSLOT" {
+                return Frame::Integer(keys.len() as i64);
+            }
+            let count = match String::from_utf8_lossy(&args[3]).parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => return Frame::Error("ERR Invalid number of keys".to_string()),
+            };
+            keys.sort();
+            Frame::Array(
+                keys.into_iter()
+                    .take(count)
+                    .map(|key| Frame::bulk(key))
+                    .collect(),
+            )
+        }
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand.to_lowercase()
+        )),
+    }
+}
+
+/// `MIGRATE host port key destination-db timeout`
+///
+/// Copies `key` to the target node with `ASKING` + `SET` and deletes it
+/// locally once the target acknowledged it.
+pub async fn migrate(db: &Db, scripts: &Scripts, args: &[Bytes]) -> Frame {
+    if args.len() != 6 {
+        return cmd::wrong_arity("migrate");
+    }
+    let addr = format!(
+        "{}:{}",
+        String::from_utf8_lossy(&args[1]),
+        String::from_utf8_lossy(&args[2])
+    );
+    let key = String::from_utf8_lossy(&args[3]).into_owned();
+    let timeout = match String::from_utf8_lossy(&args[5]).parse::<u64>() {
+        Ok(ms) => Duration::from_millis(ms.max(1)),
+        Err(_) => return Frame::Error("ERR timeout is not an integer or out of range".to_string()),
+    };
+    let value = match scripts.lock(db).await {
+        Ok(state) => match state.entries.get(&key) {
+            Some(value) => value.clone(),
+            None => return Frame::Simple("NOKEY".to_string()),
+        },
+        Err(busy) => return busy,
+    };
+
+    let transfer = async {
+        let mut target = Connection::new(TcpStream::connect(&addr).await?);
+        for command in [
+            vec![Frame::bulk("ASKING")],
+            vec![
+                Frame::bulk("SET"),
+                Frame::bulk(&key),
+                Frame::Bulk(value.clone()),
+            ],
+        ] {
+            target.write_frame(&Frame::Array(command)).await?;
+            match target.read_frame().await? {
+                Some(Frame::Error(e)) => return Err(e.into()),
+                Some(_) => {}
+                None => return Err("connection closed".into()),
+            }
+        }
+        crate::Result::Ok(())
+    };
+    match time::timeout(timeout, transfer).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            return Frame::Error(format!(
+                "IOERR error or timeout migrating to target instance: {}",
+                e
+            ))
+        }
+        Err(_) => {
+            return Frame::Error("IOERR error or timeout migrating to target instance".to_string())
+        }
+    }
+
+    // Leave the key alone if it was overwritten while the copy was in flight.
+    let mut state = match scripts.lock(db).await {
+        Ok(state) => state,
+        Err(busy) => return busy,
+    };
+    if state.entries.get(&key) == Some(&value) {
+        state.entries.remove(&key);
+    }
+    Frame::Simple("OK".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(11058, key_slot(b"somekey"));
+        assert_eq!(2515, key_slot(b"foo{hash_tag}"));
+        assert_eq!(key_slot(b"user1000"), key_slot(b"{user1000}.followers"));
+        // Empty or unterminated tags hash the whole key.
+        assert_eq!(crc16(b"{}foo") % 16384, key_slot(b"{}foo"));
+        assert_eq!(crc16(b"foo{bar") % 16384, key_slot(b"foo{bar"));
+        // Only the first tag counts.
+        assert_eq!(key_slot(b"bar"), key_slot(b"foo{bar}{zap}"));
+    }
+
+    #[test]
+    fn even_split() {
+        let addrs = ["a:1", "b:2", "c:3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let cluster = Cluster::new(&addrs, "b:2").unwrap();
+        assert_eq!(
+            vec![(0, 5461, 0), (5462, 10922, 1), (10923, 16383, 2)],
+            cluster.ranges()
+        );
+        assert!(Cluster::new(&addrs, "d:4").is_err());
+
+        let state = State::default();
+        let keys = [Bytes::from("a"), Bytes::from("b")];
+        assert_eq!(
+            Some(Frame::Error(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string()
+            )),
+            cluster.redirect(&state, &keys, false)
+        );
+        let keys = [Bytes::from("{a}x"), Bytes::from("{a}y")];
+        assert_eq!(
+            Some(Frame::Error(format!("MOVED {} c:3", key_slot(b"a")))),
+            cluster.redirect(&state, &keys, false)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::db::State;
+use crate::frame::Frame;
+use crate::{bitmap, cluster, hyperloglog, scripting};
+use bytes::Bytes;
+
+/// Split a command frame into its name and arguments.
+pub fn parse(frame: Frame) -> Result<Vec<Bytes>, String> {
+    let items = match frame {
+        Frame::Array(items) if !items.is_empty() => items,
+        frame => {
+            return Err(format!(
+                "ERR protocol error; expected array, got {:?}",
+                frame
+            ))
+        }
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            Frame::Bulk(data) => Ok(data),
+            Frame::Simple(s) => Ok(Bytes::from(s)),
+            Frame::Integer(n) => Ok(Bytes::from(n.to_string())),
+            frame => Err(format!("ERR protocol error; unexpected {:?}", frame)),
+        })
+        .collect()
+}
+
+/// Upper-cased command name, used for dispatch.
+pub fn name(args: &[Bytes]) -> String {
+    String::from_utf8_lossy(&args[0]).to_uppercase()
+}
+
+pub fn wrong_arity(name: &str) -> Frame {
+    Frame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+/// The arguments of a command that are keys, used for cluster redirection.
+pub fn keys(args: &[Bytes]) -> &[Bytes] {
+    match name(args).as_str() {
+        "GET" | "SET" | "INCR" | "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "PFADD"
+            if args.len() > 1 =>
+        {
+            &args[1..2]
+        }
+        "PFCOUNT" | "PFMERGE" => &args[1..],
+        "BITOP" if args.len() > 2 => &args[2..],
+        "EVAL" | "EVALSHA" => scripting::keys(args),
+        _ => &[],
+    }
+}
+
+/// Commands that modify the store. A script that ran one of them can no
+/// longer be killed.
+pub fn is_write(name: &str) -> bool {
+    matches!(
+        name,
+        "SET" | "INCR" | "SETBIT" | "BITOP" | "PFADD" | "PFMERGE"
+    )
+}
+
+pub fn key(arg: &Bytes) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+pub fn int_arg(arg: &Bytes) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Run a data command against the locked store.
+pub fn execute(state: &mut State, args: &[Bytes]) -> Frame {
+    let name = name(args);
+    match (name.as_str(), args.len()) {
+        ("PING", 1) => Frame::Simple("PONG".to_string()),
+        ("PING", 2) => Frame::Bulk(args[1].clone()),
+        ("ECHO", 2) => Frame::Bulk(args[1].clone()),
+        ("GET", 2) => match state.entries.get(&key(&args[1])) {
+            Some(value) => Frame::Bulk(value.clone()),
+            None => Frame::Null,
+        },
+        ("SET", 3) => {
+            state.entries.insert(key(&args[1]), args[2].clone());
+            Frame::Simple("OK".to_string())
+        }
+        ("INCR", 2) => incr(state, key(&args[1])),
+        ("SETBIT", _) => bitmap::setbit(state, args),
+        ("GETBIT", _) => bitmap::getbit(state, args),
+        ("BITCOUNT", _) => bitmap::bitcount(state, args),
+        ("BITPOS", _) => bitmap::bitpos(state, args),
+        ("BITOP", _) => bitmap::bitop(state, args),
+        ("PFADD", _) => hyperloglog::pfadd(state, args),
+        ("PFCOUNT", _) => hyperloglog::pfcount(state, args),
+        ("PFMERGE", _) => hyperloglog::pfmerge(state, args),
+        ("CLUSTER", _) => cluster::command(state, args),
+        ("PUBLISH", 3) => {
+            let n = state.publish(&key(&args[1]), args[2].clone());
+            Frame::Integer(n as i64)
+        }
+        ("PING", _) | ("ECHO", _) | ("GET", _) | ("SET", _) | ("INCR", _) | ("PUBLISH", _) => {
+            wrong_arity(&name)
+        }
+        _ => Frame::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+    }
+}
+
+/// `INCR key`, counting from 0 for a missing key.
+fn incr(state: &mut State, key: String) -> Frame {
+    let n = match state.entries.get(&key) {
+        Some(value) => match int_arg(value) {
+            Some(n) => n,
+            None => return Frame::Error("ERR value is not an integer or out of range".to_string()),
+        },
+        None => 0,
+    };
+    match n.checked_add(1) {
+        Some(n) => {
+            state.entries.insert(key, Bytes::from(n.to_string()));
+            Frame::Integer(n)
+        }
+        None => Frame::Error("ERR increment or decrement would overflow".to_string()),
+    }
+}
@@ -0,0 +1,71 @@
+use crate::frame::{self, Frame, Protocol};
+use bytes::{Buf, BytesMut};
+use std::io::{self, Cursor};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+
+/// Send and receive `Frame` values over a `TcpStream`, encoding replies for
+/// the protocol version the client negotiated with `HELLO`.
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+    // The least the buffer must hold before a partial frame is worth parsing
+    // again.
+    needed: usize,
+    pub protocol: Protocol,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Connection {
+        Connection {
+            stream: BufWriter::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+            needed: 0,
+            protocol: Protocol::Resp2,
+        }
+    }
+
+    /// Read a single frame, returning `None` when the peer closed the
+    /// connection cleanly. Safe to use as a `select!` branch, partially
+    /// received frames stay in the buffer.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            if self.buffer.len() >= self.needed {
+                if let Some(frame) = self.parse_frame()? {
+                    return Ok(Some(frame));
+                }
+            }
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        let mut buf = Cursor::new(&self.buffer[..]);
+        match Frame::parse(&mut buf) {
+            Ok(frame) => {
+                let len = buf.position() as usize;
+                self.buffer.advance(len);
+                self.needed = 0;
+                Ok(Some(frame))
+            }
+            Err(frame::Error::Incomplete(needed)) => {
+                self.needed = needed;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf, self.protocol);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+}
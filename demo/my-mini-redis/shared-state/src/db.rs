@@ -0,0 +1,40 @@
+use crate::cluster::Cluster;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+pub type Db = Arc<Mutex<State>>;
+
+/// Everything shared between connections. Commands run while holding the
+/// lock, so each one is applied atomically.
+#[derive(Default)]
+pub struct State {
+    pub entries: HashMap<String, Bytes>,
+    pub cluster: Option<Cluster>,
+    pub_sub: HashMap<String, broadcast::Sender<Bytes>>,
+}
+
+impl State {
+    pub fn subscribe(&mut self, channel: &str) -> broadcast::Receiver<Bytes> {
+        self.pub_sub
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(1024).0)
+            .subscribe()
+    }
+
+    /// Returns the number of subscribers the message was delivered to.
+    pub fn publish(&mut self, channel: &str, message: Bytes) -> usize {
+        match self.pub_sub.get(channel) {
+            Some(tx) => match tx.send(message) {
+                Ok(n) => n,
+                Err(_) => {
+                    // Every subscriber has gone away.
+                    self.pub_sub.remove(channel);
+                    0
+                }
+            },
+            None => 0,
+        }
+    }
+}
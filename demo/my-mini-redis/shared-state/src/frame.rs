@@ -0,0 +1,521 @@
+//! Redis protocol frames, covering both RESP2 and the RESP3 types negotiated
+//! with `HELLO 3`.
+
+use bytes::{Buf, Bytes};
+use std::fmt;
+use std::io::Cursor;
+
+/// Nested aggregates deeper than this are rejected instead of recursing.
+const MAX_DEPTH: usize = 32;
+
+/// Longest blob a client may announce, Redis's default `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Most items a client may announce for an aggregate, the limit Redis puts on
+/// clients that haven't authenticated.
+const MAX_AGGREGATE_LEN: usize = 1024 * 1024;
+
+/// Longest line outside a blob, like Redis's limit on inline requests.
+const MAX_LINE: usize = 64 * 1024;
+
+/// No frame is shorter than this, `_\r\n` for instance.
+const MIN_FRAME: usize = 3;
+
+/// Protocol version spoken on a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    Verbatim { format: String, data: Bytes },
+    Push(Vec<Frame>),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a message, which is at least
+    /// this many bytes long
+    Incomplete(usize),
+
+    /// Invalid message encoding
+    Other(String),
+}
+
+impl Frame {
+    pub fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    /// Parse a complete frame from `src`, advancing the cursor past it.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        parse(src, 0)
+    }
+
+    /// Encode the frame for a peer speaking `protocol`. RESP3 only types are
+    /// downgraded to their closest RESP2 equivalent.
+    pub fn encode(&self, dst: &mut Vec<u8>, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Frame::Simple(s) => line(dst, b'+', s.as_bytes()),
+            Frame::Error(s) => line(dst, b'-', s.as_bytes()),
+            Frame::Integer(n) => line(dst, b':', n.to_string().as_bytes()),
+            Frame::Bulk(data) => blob(dst, b'$', data),
+            Frame::Null if resp3 => dst.extend_from_slice(b"_\r\n"),
+            Frame::Null => dst.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(items) => aggregate(dst, b'*', items, protocol),
+            Frame::Map(entries) if resp3 => {
+                line(dst, b'%', entries.len().to_string().as_bytes());
+                for (key, value) in entries {
+                    key.encode(dst, protocol);
+                    value.encode(dst, protocol);
+                }
+            }
+            Frame::Map(entries) => {
+                line(dst, b'*', (entries.len() * 2).to_string().as_bytes());
+                for (key, value) in entries {
+                    key.encode(dst, protocol);
+                    value.encode(dst, protocol);
+                }
+            }
+            Frame::Set(items) if resp3 => aggregate(dst, b'~', items, protocol),
+            Frame::Push(items) if resp3 => aggregate(dst, b'>', items, protocol),
+            Frame::Set(items) | Frame::Push(items) => aggregate(dst, b'*', items, protocol),
+            Frame::Double(n) if resp3 => line(dst, b',', format_double(*n).as_bytes()),
+            Frame::Double(n) => blob(dst, b'$', format_double(*n).as_bytes()),
+            Frame::Boolean(b) if resp3 => line(dst, b'#', if *b { b"t" } else { b"f" }),
+            Frame::Boolean(b) => line(dst, b':', if *b { b"1" } else { b"0" }),
+            Frame::BigNumber(n) if resp3 => line(dst, b'(', n.as_bytes()),
+            Frame::BigNumber(n) => blob(dst, b'$', n.as_bytes()),
+            Frame::Verbatim { format, data } if resp3 => {
+                let mut payload = Vec::with_capacity(format.len() + 1 + data.len());
+                payload.extend_from_slice(format.as_bytes());
+                payload.push(b':');
+                payload.extend_from_slice(data);
+                blob(dst, b'=', &payload);
+            }
+            Frame::Verbatim { data, .. } => blob(dst, b'$', data),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete(_) => write!(f, "stream ended early"),
+            Error::Other(msg) => write!(f, "protocol error; {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn line(dst: &mut Vec<u8>, prefix: u8, body: &[u8]) {
+    dst.push(prefix);
+    dst.extend_from_slice(body);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn blob(dst: &mut Vec<u8>, prefix: u8, data: &[u8]) {
+    line(dst, prefix, data.len().to_string().as_bytes());
+    dst.extend_from_slice(data);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn aggregate(dst: &mut Vec<u8>, prefix: u8, items: &[Frame], protocol: Protocol) {
+    line(dst, prefix, items.len().to_string().as_bytes());
+    for item in items {
+        item.encode(dst, protocol);
+    }
+}
+
+fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+fn parse(src: &mut Cursor<&[u8]>, depth: usize) -> Result<Frame, Error> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("nesting too deep"));
+    }
+    match get_u8(src)? {
+        b'+' => Ok(Frame::Simple(get_string(src)?)),
+        b'-' => Ok(Frame::Error(get_string(src)?)),
+        b':' => Ok(Frame::Integer(get_decimal(src)?)),
+        b'$' => match get_length(src, MAX_BULK_LEN)? {
+            None => Ok(Frame::Null),
+            Some(len) => Ok(Frame::Bulk(get_blob(src, len)?)),
+        },
+        b'*' => match get_length(src, MAX_AGGREGATE_LEN)? {
+            None => Ok(Frame::Null),
+            Some(len) => Ok(Frame::Array(get_items(src, len, depth)?)),
+        },
+        b'~' => {
+            let len = get_length(src, MAX_AGGREGATE_LEN)?.ok_or_else(|| invalid("null set"))?;
+            Ok(Frame::Set(get_items(src, len, depth)?))
+        }
+        b'>' => {
+            let len = get_length(src, MAX_AGGREGATE_LEN)?.ok_or_else(|| invalid("null push"))?;
+            Ok(Frame::Push(get_items(src, len, depth)?))
+        }
+        b'%' => {
+            let len = get_length(src, MAX_AGGREGATE_LEN)?.ok_or_else(|| invalid("null map"))?;
+            let mut entries = Vec::with_capacity(len.min(1024));
+            for i in 0..len {
+                // Each entry still to come is two frames.
+                let rest = (len - i) * 2;
+                let key = need_more(parse(src, depth + 1), rest - 1)?;
+                let value = need_more(parse(src, depth + 1), rest - 2)?;
+                entries.push((key, value));
+            }
+            Ok(Frame::Map(entries))
+        }
+        b'_' => {
+            if !get_line(src)?.is_empty() {
+                return Err(invalid("null with payload"));
+            }
+            Ok(Frame::Null)
+        }
+        b',' => {
+            let line = get_string(src)?;
+            let n = line.parse::<f64>().map_err(|_| invalid("invalid double"))?;
+            Ok(Frame::Double(n))
+        }
+        b'#' => match get_line(src)? {
+            b"t" => Ok(Frame::Boolean(true)),
+            b"f" => Ok(Frame::Boolean(false)),
+            _ => Err(invalid("invalid boolean")),
+        },
+        b'(' => {
+            let n = get_string(src)?;
+            let digits = n.strip_prefix(|c| c == '-' || c == '+').unwrap_or(&n);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid("invalid big number"));
+            }
+            Ok(Frame::BigNumber(n))
+        }
+        b'=' => {
+            let len =
+                get_length(src, MAX_BULK_LEN)?.ok_or_else(|| invalid("null verbatim string"))?;
+            let payload = get_blob(src, len)?;
+            if payload.len() < 4 || payload[3] != b':' {
+                return Err(invalid("invalid verbatim string"));
+            }
+            let format = String::from_utf8(payload[..3].to_vec())
+                .map_err(|_| invalid("invalid verbatim format"))?;
+            Ok(Frame::Verbatim {
+                format,
+                data: payload.slice(4..),
+            })
+        }
+        actual => Err(invalid(&format!("invalid frame type byte `{}`", actual))),
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::Other(msg.to_string())
+}
+
+fn get_items(src: &mut Cursor<&[u8]>, len: usize, depth: usize) -> Result<Vec<Frame>, Error> {
+    // Don't trust the announced length for the allocation, a short frame may
+    // claim billions of entries.
+    let mut items = Vec::with_capacity(len.min(1024));
+    for i in 0..len {
+        items.push(need_more(parse(src, depth + 1), len - i - 1)?);
+    }
+    Ok(items)
+}
+
+/// Add the least room `frames` more frames take to an incomplete `result`.
+/// With that the connection knows not to parse again until they could have
+/// arrived, instead of going over the same big aggregate after every read.
+fn need_more<T>(result: Result<T, Error>, frames: usize) -> Result<T, Error> {
+    result.map_err(|e| match e {
+        Error::Incomplete(len) => Error::Incomplete(len.saturating_add(frames * MIN_FRAME)),
+        e => e,
+    })
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete(src.position() as usize + 1));
+    }
+    Ok(src.get_u8())
+}
+
+/// Read up to the next `\r\n`, returning the line without the terminator.
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+    match buf[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(offset) => {
+            src.set_position((start + offset + 2) as u64);
+            Ok(&buf[start..start + offset])
+        }
+        None if buf.len() - start > MAX_LINE => Err(invalid("line too long")),
+        None => Err(Error::Incomplete(buf.len() + 1)),
+    }
+}
+
+fn get_string(src: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    let line = get_line(src)?;
+    String::from_utf8(line.to_vec()).map_err(|_| invalid("invalid utf-8"))
+}
+
+fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| invalid("invalid integer"))
+}
+
+/// Length of a blob or aggregate, `None` for the RESP2 `-1` null marker.
+/// Lengths over `max` are rejected before any of it is buffered.
+fn get_length(src: &mut Cursor<&[u8]>, max: usize) -> Result<Option<usize>, Error> {
+    match get_decimal(src)? {
+        -1 => Ok(None),
+        n if n >= 0 && n as u64 <= max as u64 => Ok(Some(n as usize)),
+        _ => Err(invalid("invalid length")),
+    }
+}
+
+fn get_blob(src: &mut Cursor<&[u8]>, len: usize) -> Result<Bytes, Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+    let end = start
+        .checked_add(len)
+        .ok_or_else(|| invalid("invalid length"))?;
+    if buf.len() < end.saturating_add(2) {
+        return Err(Error::Incomplete(end.saturating_add(2)));
+    }
+    if &buf[end..end + 2] != b"\r\n" {
+        return Err(invalid("missing blob terminator"));
+    }
+    src.set_position((end + 2) as u64);
+    Ok(Bytes::copy_from_slice(&buf[start..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn parse_all(buf: &[u8]) -> Result<Frame, Error> {
+        let mut cursor = Cursor::new(buf);
+        let frame = Frame::parse(&mut cursor)?;
+        assert_eq!(buf.len() as u64, cursor.position(), "trailing bytes");
+        Ok(frame)
+    }
+
+    fn encode(frame: &Frame, protocol: Protocol) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf, protocol);
+        buf
+    }
+
+    fn random_bytes(rng: &mut StdRng) -> Bytes {
+        let len = rng.gen_range(0..16);
+        (0..len)
+            .map(|_| rng.gen::<u8>())
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    fn random_line(rng: &mut StdRng) -> String {
+        let len = rng.gen_range(0..16);
+        (0..len).map(|_| rng.gen_range(' '..='~')).collect()
+    }
+
+    fn random_frame(rng: &mut StdRng, depth: usize) -> Frame {
+        let kinds = if depth > 3 { 9 } else { 13 };
+        match rng.gen_range(0..kinds) {
+            0 => Frame::Simple(random_line(rng)),
+            1 => Frame::Error(random_line(rng)),
+            2 => Frame::Integer(rng.gen()),
+            3 => Frame::Bulk(random_bytes(rng)),
+            4 => Frame::Null,
+            5 => match rng.gen_range(0..3) {
+                0 => Frame::Double(f64::INFINITY),
+                1 => Frame::Double(rng.gen_range(-1e9..1e9)),
+                _ => Frame::Double(rng.gen::<i32>() as f64),
+            },
+            6 => Frame::Boolean(rng.gen()),
+            7 => Frame::BigNumber(format!("-{}{}", rng.gen::<u64>(), rng.gen::<u64>())),
+            8 => Frame::Verbatim {
+                format: "txt".to_string(),
+                data: random_bytes(rng),
+            },
+            n => {
+                let len = rng.gen_range(0..5);
+                let mut items = (0..len * 2)
+                    .map(|_| random_frame(rng, depth + 1))
+                    .collect::<Vec<Frame>>();
+                match n {
+                    9 => Frame::Array(items),
+                    10 => Frame::Set(items),
+                    11 => Frame::Push(items),
+                    _ => {
+                        let values = items.split_off(len);
+                        Frame::Map(items.into_iter().zip(values).collect())
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resp3_types() {
+        let cases = vec![
+            (
+                &b"%2\r\n+a\r\n:1\r\n+b\r\n#f\r\n"[..],
+                Frame::Map(vec![
+                    (Frame::Simple("a".into()), Frame::Integer(1)),
+                    (Frame::Simple("b".into()), Frame::Boolean(false)),
+                ]),
+            ),
+            (
+                b"~2\r\n:1\r\n:2\r\n",
+                Frame::Set(vec![Frame::Integer(1), Frame::Integer(2)]),
+            ),
+            (b",3.25\r\n", Frame::Double(3.25)),
+            (b",-inf\r\n", Frame::Double(f64::NEG_INFINITY)),
+            (b"#t\r\n", Frame::Boolean(true)),
+            (
+                b"(3492890328409238509324850943850943825024385\r\n",
+                Frame::BigNumber("3492890328409238509324850943850943825024385".into()),
+            ),
+            (
+                b"=15\r\ntxt:Some string\r\n",
+                Frame::Verbatim {
+                    format: "txt".into(),
+                    data: Bytes::from_static(b"Some string"),
+                },
+            ),
+            (b"_\r\n", Frame::Null),
+            (
+                b">2\r\n$7\r\nmessage\r\n$2\r\nhi\r\n",
+                Frame::Push(vec![Frame::bulk("message"), Frame::bulk("hi")]),
+            ),
+        ];
+        for (buf, frame) in cases {
+            assert_eq!(frame, parse_all(buf).unwrap());
+            assert_eq!(buf, &encode(&frame, Protocol::Resp3)[..]);
+        }
+        assert!(matches!(parse_all(b",nan\r\n").unwrap(), Frame::Double(n) if n.is_nan()));
+    }
+
+    #[test]
+    fn resp2_downgrade() {
+        let map = Frame::Map(vec![(Frame::bulk("proto"), Frame::Integer(2))]);
+        assert_eq!(
+            &b"*2\r\n$5\r\nproto\r\n:2\r\n"[..],
+            &encode(&map, Protocol::Resp2)[..]
+        );
+        assert_eq!(&b"$-1\r\n"[..], &encode(&Frame::Null, Protocol::Resp2)[..]);
+        assert_eq!(
+            &b":1\r\n"[..],
+            &encode(&Frame::Boolean(true), Protocol::Resp2)[..]
+        );
+        assert_eq!(
+            &b"$3\r\n1.5\r\n"[..],
+            &encode(&Frame::Double(1.5), Protocol::Resp2)[..]
+        );
+        assert_eq!(Frame::Null, parse_all(b"*-1\r\n").unwrap());
+    }
+
+    #[test]
+    fn fuzz_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..2000 {
+            let frame = random_frame(&mut rng, 0);
+            let buf = encode(&frame, Protocol::Resp3);
+            assert_eq!(frame, parse_all(&buf).unwrap());
+
+            // Every strict prefix of a valid frame needs more data, and no
+            // more than the whole frame.
+            let cut = rng.gen_range(0..buf.len());
+            match Frame::parse(&mut Cursor::new(&buf[..cut])) {
+                Err(Error::Incomplete(len)) => assert!(cut < len && len <= buf.len()),
+                result => panic!("{:?} from a prefix of {:?}", result, frame),
+            }
+
+            // RESP2 output must still be parseable.
+            parse_all(&encode(&frame, Protocol::Resp2)).unwrap();
+        }
+    }
+
+    #[test]
+    fn fuzz_garbage() {
+        let mut rng = StdRng::seed_from_u64(0xbad);
+        for _ in 0..5000 {
+            let mut buf = encode(&random_frame(&mut rng, 0), Protocol::Resp3);
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..buf.len());
+                buf[i] = rng.gen();
+            }
+            // Any outcome is fine as long as the parser doesn't panic.
+            let _ = Frame::parse(&mut Cursor::new(&buf[..]));
+        }
+        let nested = "*1\r\n".repeat(MAX_DEPTH + 2);
+        assert!(matches!(
+            Frame::parse(&mut Cursor::new(nested.as_bytes())),
+            Err(Error::Other(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        let too_long =
+            |buf: &[u8]| matches!(Frame::parse(&mut Cursor::new(buf)), Err(Error::Other(_)));
+        for header in &[
+            "$9999999999\r\n",
+            "=536870913\r\n",
+            "*9999999999\r\n",
+            "~1048577\r\n",
+            ">1048577\r\n",
+            "%1048577\r\n",
+        ] {
+            assert!(too_long(header.as_bytes()), "{}", header);
+        }
+        let mut line = b"(".to_vec();
+        line.resize(MAX_LINE + 2, b'1');
+        assert!(too_long(&line));
+
+        // The largest lengths allowed just wait for their data.
+        for header in &["$536870912\r\n", "*1048576\r\n"] {
+            assert!(matches!(
+                Frame::parse(&mut Cursor::new(header.as_bytes())),
+                Err(Error::Incomplete(_))
+            ));
+        }
+    }
+}
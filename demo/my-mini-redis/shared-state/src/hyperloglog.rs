@@ -0,0 +1,331 @@
+//! HyperLogLog cardinality estimation with 2^14 registers, for a standard
+//! error of 1.04 / sqrt(16384) = 0.81%.
+//!
+//! A HyperLogLog is stored as a regular string value: the `HYLL` magic
+//! followed by one byte per register. Values without the magic are rejected
+//! by the PF commands, but GET, SET and the bit commands still work on them.
+
+use crate::cmd;
+use crate::db::State;
+use crate::frame::Frame;
+use bytes::Bytes;
+
+const P: u32 = 14;
+const REGISTERS: usize = 1 << P;
+// Bits of the hash left after taking the register index.
+const Q: u32 = 64 - P;
+const MAGIC: &[u8] = b"HYLL";
+
+/// MurmurHash64A, with the seed Redis uses for HyperLogLog.
+fn murmur64a(key: &[u8]) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h: u64 = 0xadc83b19 ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Register index and run length of leading zeros + 1 for `element`.
+fn position(element: &[u8]) -> (usize, u8) {
+    let hash = murmur64a(element);
+    let index = (hash as usize) & (REGISTERS - 1);
+    // The sentinel bit bounds the count at Q + 1.
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    /// The value's registers, which a client may have changed with SET or
+    /// SETBIT, so none may be out of range.
+    fn decode(value: &[u8]) -> Result<HyperLogLog, Frame> {
+        if value.len() != MAGIC.len() + REGISTERS || !value.starts_with(MAGIC) {
+            return Err(wrong_type());
+        }
+        let registers = &value[MAGIC.len()..];
+        if registers.iter().any(|&register| register as u32 > Q + 1) {
+            return Err(Frame::Error(
+                "INVALIDOBJ Corrupted HLL object detected".to_string(),
+            ));
+        }
+        Ok(HyperLogLog {
+            registers: registers.to_vec(),
+        })
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut value = Vec::with_capacity(MAGIC.len() + REGISTERS);
+        value.extend_from_slice(MAGIC);
+        value.extend_from_slice(&self.registers);
+        Bytes::from(value)
+    }
+
+    /// Returns true if a register changed.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = position(element);
+        if self.registers[index] < count {
+            self.registers[index] = count;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// Cardinality estimate using the improved estimator from Otmar Ertl's
+    /// "New cardinality estimation algorithms for HyperLogLog sketches",
+    /// which needs no bias correction at low cardinalities.
+    fn count(&self) -> u64 {
+        let mut histogram = [0u32; Q as usize + 2];
+        for &register in &self.registers {
+            histogram[register as usize] += 1;
+        }
+
+        let m = REGISTERS as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for k in (1..=Q as usize).rev() {
+            z += histogram[k] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        let alpha = 0.5 / std::f64::consts::LN_2;
+        (alpha * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn wrong_type() -> Frame {
+    Frame::Error("WRONGTYPE Key is not a valid HyperLogLog string value.".to_string())
+}
+
+/// Load the HyperLogLog stored at `key`. Missing keys are `Ok(None)`.
+fn load(state: &State, key: &Bytes) -> Result<Option<HyperLogLog>, Frame> {
+    match state.entries.get(&cmd::key(key)) {
+        Some(value) => HyperLogLog::decode(value).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// `PFADD key [element ...]`
+pub fn pfadd(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 2 {
+        return cmd::wrong_arity("pfadd");
+    }
+    let (mut hll, mut changed) = match load(state, &args[1]) {
+        Ok(Some(hll)) => (hll, false),
+        Ok(None) => (HyperLogLog::new(), true),
+        Err(e) => return e,
+    };
+    for element in &args[2..] {
+        changed |= hll.add(element);
+    }
+    if changed {
+        state.entries.insert(cmd::key(&args[1]), hll.encode());
+    }
+    Frame::Integer(changed as i64)
+}
+
+/// `PFCOUNT key [key ...]`, the cardinality of the union when given
+/// several keys.
+pub fn pfcount(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 2 {
+        return cmd::wrong_arity("pfcount");
+    }
+    let mut union = HyperLogLog::new();
+    for key in &args[1..] {
+        match load(state, key) {
+            Ok(Some(hll)) => union.merge(&hll),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+    }
+    Frame::Integer(union.count() as i64)
+}
+
+/// `PFMERGE destkey [sourcekey ...]`
+pub fn pfmerge(state: &mut State, args: &[Bytes]) -> Frame {
+    if args.len() < 2 {
+        return cmd::wrong_arity("pfmerge");
+    }
+    let mut union = HyperLogLog::new();
+    for key in &args[1..] {
+        match load(state, key) {
+            Ok(Some(hll)) => union.merge(&hll),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+    }
+    state.entries.insert(cmd::key(&args[1]), union.encode());
+    Frame::Simple("OK".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(state: &mut State, args: &[&str]) -> Frame {
+        let args = args
+            .iter()
+            .map(|arg| Bytes::copy_from_slice(arg.as_bytes()))
+            .collect::<Vec<Bytes>>();
+        cmd::execute(state, &args)
+    }
+
+    fn add_range(state: &mut State, key: &str, range: std::ops::Range<u32>) {
+        let mut args = vec!["PFADD".to_string(), key.to_string()];
+        args.extend(range.map(|i| format!("user:{}", i)));
+        run(state, &args.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+
+    fn count(state: &mut State, keys: &[&str]) -> f64 {
+        let mut args = vec!["PFCOUNT"];
+        args.extend(keys);
+        match run(state, &args) {
+            Frame::Integer(n) => n as f64,
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn add_and_count() {
+        let mut state = State::default();
+        assert_eq!(
+            Frame::Integer(1),
+            run(&mut state, &["PFADD", "hll", "a", "b", "c"])
+        );
+        assert_eq!(
+            Frame::Integer(0),
+            run(&mut state, &["PFADD", "hll", "a", "b"])
+        );
+        assert_eq!(Frame::Integer(0), run(&mut state, &["PFADD", "hll"]));
+        assert_eq!(Frame::Integer(1), run(&mut state, &["PFADD", "empty"]));
+        assert_eq!(3.0, count(&mut state, &["hll"]));
+        assert_eq!(0.0, count(&mut state, &["empty", "missing"]));
+
+        run(&mut state, &["SET", "plain", "value"]);
+        assert!(
+            matches!(run(&mut state, &["PFADD", "plain", "a"]), Frame::Error(e) if e.starts_with("WRONGTYPE"))
+        );
+        assert!(matches!(
+            run(&mut state, &["PFCOUNT", "plain"]),
+            Frame::Error(_)
+        ));
+        // The HyperLogLog is still a string for the rest of the commands.
+        assert!(matches!(run(&mut state, &["GET", "hll"]), Frame::Bulk(v) if v.starts_with(MAGIC)));
+    }
+
+    #[test]
+    fn corrupt_registers() {
+        let mut state = State::default();
+        run(&mut state, &["PFADD", "hll", "a"]);
+        // The last register is the last byte of the value, its top bit makes
+        // it 128.
+        let last = (MAGIC.len() + REGISTERS) * 8 - 1;
+        run(&mut state, &["SETBIT", "hll", &(last - 7).to_string(), "1"]);
+        let invalid =
+            |frame: Frame| matches!(frame, Frame::Error(e) if e.starts_with("INVALIDOBJ"));
+        assert!(invalid(run(&mut state, &["PFCOUNT", "hll"])));
+        assert!(invalid(run(&mut state, &["PFADD", "hll", "b"])));
+        assert!(invalid(run(&mut state, &["PFMERGE", "dest", "hll"])));
+
+        let mut value = MAGIC.to_vec();
+        value.resize(MAGIC.len() + REGISTERS, Q as u8 + 2);
+        state.entries.insert("forged".into(), Bytes::from(value));
+        assert!(invalid(run(&mut state, &["PFCOUNT", "forged"])));
+    }
+
+    #[test]
+    fn standard_error() {
+        let mut state = State::default();
+        for &n in &[1_000u32, 20_000, 100_000] {
+            let key = format!("dau:{}", n);
+            add_range(&mut state, &key, 0..n);
+            let error = (count(&mut state, &[&key]) - n as f64).abs() / n as f64;
+            // Three standard errors.
+            assert!(error < 3.0 * 0.0081, "n={} error={}", n, error);
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut state = State::default();
+        add_range(&mut state, "monday", 0..50_000);
+        add_range(&mut state, "tuesday", 25_000..75_000);
+        let union = count(&mut state, &["monday", "tuesday"]);
+        assert_eq!(
+            Frame::Simple("OK".into()),
+            run(&mut state, &["PFMERGE", "week", "monday", "tuesday"])
+        );
+        assert_eq!(union, count(&mut state, &["week"]));
+        assert!((union - 75_000.0).abs() / 75_000.0 < 3.0 * 0.0081);
+    }
+}
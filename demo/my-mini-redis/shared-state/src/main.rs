@@ -1,52 +1,693 @@
+mod bitmap;
+mod cluster;
+mod cmd;
+mod connection;
+mod db;
+mod frame;
+mod hyperloglog;
+mod scripting;
+
 use bytes::Bytes;
-use mini_redis::{Connection, Frame};
+use cluster::Cluster;
+use connection::Connection;
+use db::{Db, State};
+use frame::{Frame, Protocol};
+use scripting::Scripts;
 use std::collections::HashMap;
-use std::option::Option::Some;
-use std::sync::{Arc, Mutex};
+use std::env;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+static NEXT_CLIENT_ID: AtomicI64 = AtomicI64::new(1);
+
+const USAGE: &str = "Usage: shared-state [--port <port>] [--cluster <host:port>,<host:port>,...] \
+                     [--lua-time-limit <ms>]";
+
+struct Config {
+    port: u16,
+    // Every node of the cluster, including this one, when in cluster mode.
+    cluster: Vec<String>,
+    // How long a script runs before other clients get BUSY replies.
+    lua_time_limit: Duration,
+}
 
-type DB = Arc<Mutex<HashMap<String, Bytes>>>;
+impl Config {
+    fn new(args: &[String]) -> std::result::Result<Config, String> {
+        let mut config = Config {
+            port: 6379,
+            cluster: vec![],
+            lua_time_limit: Duration::from_secs(5),
+        };
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "--port" => {
+                    config.port = value
+                        .parse()
+                        .map_err(|_| format!("invalid port {:?}", value))?
+                }
+                "--cluster" => config.cluster = value.split(',').map(String::from).collect(),
+                "--lua-time-limit" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| format!("invalid time limit {:?}", value))?;
+                    config.lua_time_limit = Duration::from_millis(ms);
+                }
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    let config = Config::new(&env::args().collect::<Vec<String>>()).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(1);
+    });
+    let addr = format!("127.0.0.1:{}", config.port);
+    let db = Db::default();
+    if !config.cluster.is_empty() {
+        let cluster = Cluster::new(&config.cluster, &addr).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        db.lock().unwrap().cluster = Some(cluster);
+    }
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    let scripts = Arc::new(Scripts::new(config.lua_time_limit));
+    serve(listener, db, scripts).await;
+}
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
+async fn serve(listener: TcpListener, db: Db, scripts: Arc<Scripts>) {
     loop {
         let (socket, _) = listener.accept().await.unwrap();
+        socket.set_nodelay(true).unwrap();
         let db = db.clone();
+        let scripts = scripts.clone();
         tokio::spawn(async move {
-            // process(socket).await;
-            process(socket, db).await;
+            if let Err(e) = process(socket, db, scripts).await {
+                println!("connection error: {}", e);
+            }
         });
     }
 }
 
-#[allow(dead_code)]
-async fn process(socket: TcpStream, db: DB) {
-    use mini_redis::Command::{self, Get, Set};
+/// Per connection state that isn't part of the shared store.
+struct Client {
+    id: i64,
+    name: Option<String>,
+    // Set by `ASKING`, lets the next command run on a slot being imported.
+    asking: bool,
+    // Dropping the sender stops the forwarding task of that channel.
+    subscriptions: HashMap<String, oneshot::Sender<()>>,
+    // Messages from subscriptions, delivered as out-of-band push frames.
+    messages: mpsc::Sender<Frame>,
+}
 
+async fn process(socket: TcpStream, db: Db, scripts: Arc<Scripts>) -> Result<()> {
     let mut connection = Connection::new(socket);
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                println!("Set...");
-                let mut db = db.lock().unwrap();
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                println!("OK");
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                let db = db.lock().unwrap();
-                println!("Get...");
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone().into())
-                } else {
-                    Frame::Null
+    let (tx, mut rx) = mpsc::channel(64);
+    let mut client = Client {
+        id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+        name: None,
+        asking: false,
+        subscriptions: HashMap::new(),
+        messages: tx,
+    };
+
+    loop {
+        tokio::select! {
+            frame = connection.read_frame() => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                let responses = match cmd::parse(frame) {
+                    Ok(args) => run(&mut connection, &mut client, &db, &scripts, &args).await,
+                    Err(e) => vec![Frame::Error(e)],
+                };
+                for response in responses {
+                    connection.write_frame(&response).await?;
+                }
+            }
+            Some(message) = rx.recv() => connection.write_frame(&message).await?,
+        }
+    }
+}
+
+/// Commands that wait on other tasks are run here, the rest go through
+/// `dispatch`. Apart from `SCRIPT`, nothing runs while a script holds the
+/// store.
+async fn run(
+    connection: &mut Connection,
+    client: &mut Client,
+    db: &Db,
+    scripts: &Arc<Scripts>,
+    args: &[Bytes],
+) -> Vec<Frame> {
+    let name = cmd::name(args);
+    if name == "SCRIPT" {
+        return vec![scripting::script(scripts, args)];
+    }
+    match name.as_str() {
+        "MIGRATE" => vec![cluster::migrate(db, scripts, args).await],
+        "EVAL" | "EVALSHA" => {
+            if let Err(busy) = scripts.ready().await {
+                return vec![busy];
+            }
+            let asking = std::mem::take(&mut client.asking);
+            vec![scripting::eval(db, scripts, args, asking).await]
+        }
+        _ => match scripts.lock(db).await {
+            Ok(mut state) => dispatch(connection, client, &mut state, args),
+            Err(busy) => vec![busy],
+        },
+    }
+}
+
+fn dispatch(
+    connection: &mut Connection,
+    client: &mut Client,
+    state: &mut State,
+    args: &[Bytes],
+) -> Vec<Frame> {
+    match cmd::name(args).as_str() {
+        "HELLO" => vec![hello(connection, client, state, args)],
+        "SUBSCRIBE" if args.len() > 1 => args[1..]
+            .iter()
+            .map(|channel| subscribe(client, state, String::from_utf8_lossy(channel).into_owned()))
+            .collect(),
+        "SUBSCRIBE" => vec![cmd::wrong_arity("subscribe")],
+        "UNSUBSCRIBE" => {
+            let mut channels = args[1..]
+                .iter()
+                .map(|channel| String::from_utf8_lossy(channel).into_owned())
+                .collect::<Vec<String>>();
+            if channels.is_empty() {
+                channels = client.subscriptions.keys().cloned().collect();
+                channels.sort();
+            }
+            if channels.is_empty() {
+                return vec![Frame::Push(vec![
+                    Frame::bulk("unsubscribe"),
+                    Frame::Null,
+                    Frame::Integer(0),
+                ])];
+            }
+            channels
+                .into_iter()
+                .map(|channel| unsubscribe(client, channel))
+                .collect()
+        }
+        "ASKING" => {
+            if state.cluster.is_none() {
+                return vec![Frame::Error(
+                    "ERR This instance has cluster support disabled".to_string(),
+                )];
+            }
+            client.asking = true;
+            vec![Frame::Simple("OK".to_string())]
+        }
+        _ => {
+            let asking = std::mem::take(&mut client.asking);
+            if let Some(redirect) = cluster::redirect(state, args, asking) {
+                return vec![redirect];
+            }
+            vec![cmd::execute(state, args)]
+        }
+    }
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+fn hello(connection: &mut Connection, client: &mut Client, state: &State, args: &[Bytes]) -> Frame {
+    let mut protocol = connection.protocol;
+    if let Some(version) = args.get(1) {
+        protocol = match &version[..] {
+            b"2" => Protocol::Resp2,
+            b"3" => Protocol::Resp3,
+            _ => return Frame::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+    }
+
+    let mut name = client.name.clone();
+    let mut options = args.iter().skip(2);
+    while let Some(option) = options.next() {
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            // There are no users configured, any credentials are accepted.
+            "AUTH" if options.len() >= 2 => {
+                options.next();
+                options.next();
+            }
+            "SETNAME" if options.len() >= 1 => {
+                name = options
+                    .next()
+                    .map(|n| String::from_utf8_lossy(n).into_owned());
+            }
+            _ => return Frame::Error("ERR syntax error in HELLO option".to_string()),
+        }
+    }
+
+    // The reply is already encoded with the newly negotiated protocol.
+    connection.protocol = protocol;
+    client.name = name;
+    let mode = match state.cluster {
+        Some(_) => "cluster",
+        None => "standalone",
+    };
+    Frame::Map(vec![
+        (Frame::bulk("server"), Frame::bulk("redis")),
+        (
+            Frame::bulk("version"),
+            Frame::bulk(env!("CARGO_PKG_VERSION")),
+        ),
+        (Frame::bulk("proto"), Frame::Integer(protocol.version())),
+        (Frame::bulk("id"), Frame::Integer(client.id)),
+        (Frame::bulk("mode"), Frame::bulk(mode)),
+        (Frame::bulk("role"), Frame::bulk("master")),
+        (Frame::bulk("modules"), Frame::Array(vec![])),
+    ])
+}
+
+fn subscribe(client: &mut Client, state: &mut State, channel: String) -> Frame {
+    if !client.subscriptions.contains_key(&channel) {
+        let mut rx = state.subscribe(&channel);
+        let tx = client.messages.clone();
+        let (stop, mut stopped) = oneshot::channel();
+        let name = channel.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    message = rx.recv() => message,
+                    _ = &mut stopped => break,
+                };
+                match message {
+                    Ok(message) => {
+                        let push = Frame::Push(vec![
+                            Frame::bulk("message"),
+                            Frame::bulk(&name),
+                            Frame::Bulk(message),
+                        ]);
+                        if tx.send(push).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
-            cmd => panic!("unimplemented {:?}", cmd),
+        });
+        client.subscriptions.insert(channel.clone(), stop);
+    }
+    Frame::Push(vec![
+        Frame::bulk("subscribe"),
+        Frame::bulk(&channel),
+        Frame::Integer(client.subscriptions.len() as i64),
+    ])
+}
+
+fn unsubscribe(client: &mut Client, channel: String) -> Frame {
+    client.subscriptions.remove(&channel);
+    Frame::Push(vec![
+        Frame::bulk("unsubscribe"),
+        Frame::bulk(&channel),
+        Frame::Integer(client.subscriptions.len() as i64),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scripts() -> Arc<Scripts> {
+        Arc::new(Scripts::new(Duration::from_secs(5)))
+    }
+
+    async fn start() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Db::default(), scripts()));
+        addr
+    }
+
+    async fn connect(addr: std::net::SocketAddr, protocol: Protocol) -> Connection {
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        connection.protocol = protocol;
+        connection
+    }
+
+    async fn call(connection: &mut Connection, args: &[&str]) -> Frame {
+        let frame = Frame::Array(args.iter().map(|arg| Frame::bulk(arg)).collect());
+        connection.write_frame(&frame).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_protocol() {
+        let addr = start().await;
+        let mut c = connect(addr, Protocol::Resp3).await;
+
+        match call(&mut c, &["HELLO", "3", "SETNAME", "bench"]).await {
+            Frame::Map(entries) => {
+                assert!(entries.contains(&(Frame::bulk("proto"), Frame::Integer(3))))
+            }
+            frame => panic!("expected map, got {:?}", frame),
+        }
+        assert_eq!(Frame::Null, call(&mut c, &["GET", "missing"]).await);
+        assert_eq!(
+            Frame::Error("NOPROTO unsupported protocol version".into()),
+            call(&mut c, &["HELLO", "4"]).await
+        );
+
+        // RESP2 clients get the map flattened into an array.
+        let mut c2 = connect(addr, Protocol::Resp3).await;
+        assert!(
+            matches!(call(&mut c2, &["HELLO", "2"]).await, Frame::Array(items) if items.len() == 14)
+        );
+    }
+
+    #[tokio::test]
+    async fn incr_counts() {
+        let addr = start().await;
+        let mut c = connect(addr, Protocol::Resp2).await;
+        assert_eq!(Frame::Integer(1), call(&mut c, &["INCR", "hits"]).await);
+        assert_eq!(Frame::Integer(2), call(&mut c, &["incr", "hits"]).await);
+        assert_eq!(Frame::bulk("2"), call(&mut c, &["GET", "hits"]).await);
+        call(&mut c, &["SET", "low", "-3"]).await;
+        assert_eq!(Frame::Integer(-2), call(&mut c, &["INCR", "low"]).await);
+
+        call(&mut c, &["SET", "name", "bench"]).await;
+        assert_eq!(
+            Frame::Error("ERR value is not an integer or out of range".into()),
+            call(&mut c, &["INCR", "name"]).await
+        );
+        call(&mut c, &["SET", "max", &i64::MAX.to_string()]).await;
+        assert_eq!(
+            Frame::Error("ERR increment or decrement would overflow".into()),
+            call(&mut c, &["INCR", "max"]).await
+        );
+        assert_eq!(
+            Frame::Error("ERR wrong number of arguments for 'incr' command".into()),
+            call(&mut c, &["INCR"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn pub_sub_push_messages() {
+        let addr = start().await;
+        let mut subscriber = connect(addr, Protocol::Resp3).await;
+        let mut publisher = connect(addr, Protocol::Resp3).await;
+        call(&mut subscriber, &["HELLO", "3"]).await;
+
+        assert_eq!(
+            Frame::Push(vec![
+                Frame::bulk("subscribe"),
+                Frame::bulk("news"),
+                Frame::Integer(1)
+            ]),
+            call(&mut subscriber, &["SUBSCRIBE", "news"]).await
+        );
+        assert_eq!(
+            Frame::Integer(1),
+            call(&mut publisher, &["PUBLISH", "news", "hi"]).await
+        );
+        assert_eq!(
+            Frame::Push(vec![
+                Frame::bulk("message"),
+                Frame::bulk("news"),
+                Frame::bulk("hi")
+            ]),
+            subscriber.read_frame().await.unwrap().unwrap()
+        );
+
+        // Regular commands keep working on a subscribed RESP3 connection.
+        assert_eq!(
+            Frame::Simple("OK".into()),
+            call(&mut subscriber, &["SET", "k", "v"]).await
+        );
+        assert_eq!(
+            Frame::Push(vec![
+                Frame::bulk("unsubscribe"),
+                Frame::bulk("news"),
+                Frame::Integer(0)
+            ]),
+            call(&mut subscriber, &["UNSUBSCRIBE"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn eval_scripts() {
+        let addr = start().await;
+        let mut connection = connect(addr, Protocol::Resp2).await;
+
+        // Compare-and-set, applied atomically.
+        let cas = "if redis.call('GET', KEYS[1]) == ARGV[1] then \
+                   return redis.call('SET', KEYS[1], ARGV[2]) end return false";
+        call(&mut connection, &["SET", "lock", "a"]).await;
+        assert_eq!(
+            Frame::Simple("OK".into()),
+            call(&mut connection, &["EVAL", cas, "1", "lock", "a", "b"]).await
+        );
+        assert_eq!(
+            Frame::Null,
+            call(&mut connection, &["EVAL", cas, "1", "lock", "a", "c"]).await
+        );
+        assert_eq!(
+            Frame::bulk("b"),
+            call(&mut connection, &["GET", "lock"]).await
+        );
+
+        let sha = match call(
+            &mut connection,
+            &["SCRIPT", "LOAD", "return {1, 'two', {3}}"],
+        )
+        .await
+        {
+            Frame::Bulk(sha) => String::from_utf8(sha.to_vec()).unwrap(),
+            frame => panic!("unexpected {:?}", frame),
         };
-        connection.write_frame(&response).await.unwrap();
+        assert_eq!(40, sha.len());
+        assert_eq!(
+            Frame::Array(vec![
+                Frame::Integer(1),
+                Frame::bulk("two"),
+                Frame::Array(vec![Frame::Integer(3)])
+            ]),
+            call(&mut connection, &["EVALSHA", &sha, "0"]).await
+        );
+        assert_eq!(
+            Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)]),
+            call(&mut connection, &["SCRIPT", "EXISTS", &sha, "ffff"]).await
+        );
+        call(&mut connection, &["SCRIPT", "FLUSH"]).await;
+        assert!(matches!(
+            call(&mut connection, &["EVALSHA", &sha, "0"]).await,
+            Frame::Error(e) if e.starts_with("NOSCRIPT")
+        ));
+
+        // Error replies raise from redis.call and come back as tables from
+        // redis.pcall.
+        call(&mut connection, &["SET", "plain", "value"]).await;
+        assert!(matches!(
+            call(&mut connection, &["EVAL", "return redis.call('PFADD', KEYS[1], 'x')", "1", "plain"]).await,
+            Frame::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(
+            Frame::bulk("handled"),
+            call(
+                &mut connection,
+                &["EVAL", "local r = redis.pcall('PFADD', 'plain', 'x') if r.err then return 'handled' end", "0"]
+            )
+            .await
+        );
+        assert_eq!(
+            Frame::Error("MY error".into()),
+            call(
+                &mut connection,
+                &["EVAL", "return redis.error_reply('MY error')", "0"]
+            )
+            .await
+        );
+        assert!(matches!(
+            call(&mut connection, &["EVAL", "return io.open('/etc/passwd')", "0"]).await,
+            Frame::Error(e) if e.starts_with("ERR Error running script")
+        ));
+        assert!(matches!(
+            call(&mut connection, &["EVAL", "return 1", "2", "k"]).await,
+            Frame::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn script_kill() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let scripts = Arc::new(Scripts::new(Duration::from_millis(100)));
+        tokio::spawn(serve(listener, Db::default(), scripts));
+
+        let mut connection = connect(addr, Protocol::Resp2).await;
+        assert!(matches!(
+            call(&mut connection, &["SCRIPT", "KILL"]).await,
+            Frame::Error(e) if e.starts_with("NOTBUSY")
+        ));
+
+        let mut looping = connect(addr, Protocol::Resp2).await;
+        let script =
+            tokio::spawn(
+                async move { call(&mut looping, &["EVAL", "while true do end", "0"]).await },
+            );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(matches!(
+            call(&mut connection, &["SCRIPT", "KILL"]).await,
+            Frame::Error(e) if e.starts_with("ERR The script can be killed")
+        ));
+        // Commands wait for the script until it goes over the time limit.
+        assert!(matches!(
+            call(&mut connection, &["PING"]).await,
+            Frame::Error(e) if e.starts_with("BUSY")
+        ));
+        assert_eq!(
+            Frame::Simple("OK".into()),
+            call(&mut connection, &["SCRIPT", "KILL"]).await
+        );
+        assert!(matches!(
+            script.await.unwrap(),
+            Frame::Error(e) if e.contains("killed")
+        ));
+        assert_eq!(
+            Frame::Simple("PONG".into()),
+            call(&mut connection, &["PING"]).await
+        );
+    }
+
+    async fn start_cluster() -> Vec<std::net::SocketAddr> {
+        let mut listeners = vec![];
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let nodes = addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        for (listener, addr) in listeners.into_iter().zip(&nodes) {
+            let db = Db::default();
+            db.lock().unwrap().cluster = Some(Cluster::new(&nodes, addr).unwrap());
+            tokio::spawn(serve(listener, db, scripts()));
+        }
+        addrs
+    }
+
+    async fn node_id(connection: &mut Connection) -> String {
+        match call(connection, &["CLUSTER", "MYID"]).await {
+            Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_redirects_and_migration() {
+        let addrs = start_cluster().await;
+        let mut nodes = vec![];
+        for addr in &addrs {
+            nodes.push(connect(*addr, Protocol::Resp2).await);
+        }
+        let (source, target) = (addrs[2], addrs[0]);
+        let target_id = node_id(&mut nodes[0]).await;
+        let source_id = node_id(&mut nodes[2]).await;
+
+        // "foo" hashes to slot 12182, owned by the last node.
+        assert_eq!(
+            Frame::Integer(12182),
+            call(&mut nodes[0], &["CLUSTER", "KEYSLOT", "foo"]).await
+        );
+        let moved = Frame::Error(format!("MOVED 12182 {}", source));
+        assert_eq!(moved, call(&mut nodes[0], &["SET", "foo", "bar"]).await);
+        assert_eq!(
+            Frame::Simple("OK".into()),
+            call(&mut nodes[2], &["SET", "foo", "bar"]).await
+        );
+        match call(&mut nodes[1], &["CLUSTER", "SLOTS"]).await {
+            Frame::Array(ranges) => assert_eq!(3, ranges.len()),
+            frame => panic!("unexpected {:?}", frame),
+        }
+
+        // Start moving slot 12182 from the last node to the first one.
+        let ok = Frame::Simple("OK".into());
+        assert_eq!(
+            ok,
+            call(
+                &mut nodes[0],
+                &["CLUSTER", "SETSLOT", "12182", "IMPORTING", &source_id]
+            )
+            .await
+        );
+        assert_eq!(
+            ok,
+            call(
+                &mut nodes[2],
+                &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &target_id]
+            )
+            .await
+        );
+        match call(&mut nodes[2], &["CLUSTER", "NODES"]).await {
+            Frame::Bulk(info) => {
+                assert!(String::from_utf8_lossy(&info).contains(&format!("[12182->-{}]", target_id)))
+            }
+            frame => panic!("unexpected {:?}", frame),
+        }
+
+        // Keys still on the source are served there, missing ones are ASKed for.
+        assert_eq!(
+            Frame::bulk("bar"),
+            call(&mut nodes[2], &["GET", "foo"]).await
+        );
+        let ask = Frame::Error(format!("ASK 12182 {}", target));
+        assert_eq!(ask, call(&mut nodes[2], &["GET", "{foo}.missing"]).await);
+
+        let port = target.port().to_string();
+        assert_eq!(
+            ok,
+            call(
+                &mut nodes[2],
+                &["MIGRATE", "127.0.0.1", &port, "foo", "0", "1000"]
+            )
+            .await
+        );
+        assert_eq!(ask, call(&mut nodes[2], &["GET", "foo"]).await);
+        // The target only serves an importing slot right after ASKING.
+        assert_eq!(moved, call(&mut nodes[0], &["GET", "foo"]).await);
+        assert_eq!(ok, call(&mut nodes[0], &["ASKING"]).await);
+        assert_eq!(
+            Frame::bulk("bar"),
+            call(&mut nodes[0], &["GET", "foo"]).await
+        );
+
+        // Finish the migration on every node.
+        for node in nodes.iter_mut() {
+            assert_eq!(
+                ok,
+                call(node, &["CLUSTER", "SETSLOT", "12182", "NODE", &target_id]).await
+            );
+        }
+        assert_eq!(
+            Frame::bulk("bar"),
+            call(&mut nodes[0], &["GET", "foo"]).await
+        );
+        assert_eq!(
+            Frame::Error(format!("MOVED 12182 {}", target)),
+            call(&mut nodes[2], &["GET", "foo"]).await
+        );
     }
 }
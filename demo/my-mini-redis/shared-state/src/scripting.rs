@@ -0,0 +1,489 @@
+//! EVAL/EVALSHA and the SCRIPT commands, running Lua 5.1 scripts against the
+//! store.
+//!
+//! A script holds the store lock for its whole run, so it is applied
+//! atomically like any other command. Once it has been running for longer
+//! than the time limit, other clients get `BUSY` replies and `SCRIPT KILL`
+//! may abort it, unless the script already wrote to the store. Clients wait
+//! for a script on its running marker rather than the lock, so none of them
+//! sits on a worker thread waiting for it, which would leave no worker to
+//! serve `SCRIPT KILL`.
+
+use crate::cluster;
+use crate::cmd;
+use crate::db::{Db, State};
+use crate::frame::Frame;
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Value, Variadic};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time;
+
+const KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+struct Running {
+    started: Instant,
+    kill: Arc<AtomicBool>,
+    wrote: Arc<AtomicBool>,
+}
+
+pub struct Scripts {
+    cache: Mutex<HashMap<String, Bytes>>,
+    time_limit: Duration,
+    running: Mutex<Option<Running>>,
+    done: Notify,
+}
+
+impl Scripts {
+    pub fn new(time_limit: Duration) -> Scripts {
+        Scripts {
+            cache: Mutex::new(HashMap::new()),
+            time_limit,
+            running: Mutex::new(None),
+            done: Notify::new(),
+        }
+    }
+
+    fn load(&self, body: Bytes) -> String {
+        let sha = sha1_smol::Sha1::from(&body[..]).digest().to_string();
+        self.cache.lock().unwrap().insert(sha.clone(), body);
+        sha
+    }
+
+    /// Wait for a running script to finish before touching the store. Fails
+    /// with `BUSY` once the script has gone over the time limit.
+    pub async fn ready(&self) -> Result<(), Frame> {
+        loop {
+            let done = self.done.notified();
+            let deadline = match &*self.running.lock().unwrap() {
+                Some(running) => running.started + self.time_limit,
+                None => return Ok(()),
+            };
+            if Instant::now() >= deadline {
+                return Err(Frame::Error(
+                    "BUSY Redis is busy running a script. You can only call SCRIPT KILL."
+                        .to_string(),
+                ));
+            }
+            tokio::select! {
+                _ = done => {}
+                _ = time::sleep_until(deadline.into()) => {}
+            }
+        }
+    }
+
+    /// Lock the store for a command once no script is running. A script is
+    /// marked running from before it takes the store until after it lets
+    /// go, and `start` marks it under the same lock as the check here, so
+    /// the store is only ever waited for while a plain command holds it.
+    pub async fn lock<'a>(&self, db: &'a Db) -> Result<MutexGuard<'a, State>, Frame> {
+        loop {
+            self.ready().await?;
+            let running = self.running.lock().unwrap();
+            if running.is_none() {
+                // Every command leaves the store consistent before it can
+                // panic, so carry on with it.
+                return Ok(db.lock().unwrap_or_else(PoisonError::into_inner));
+            }
+        }
+    }
+
+    /// Mark a script as running before it takes the store, once the one
+    /// running before it is done. Returns its kill switch and whether it
+    /// wrote.
+    async fn start(&self) -> Result<(Arc<AtomicBool>, Arc<AtomicBool>), Frame> {
+        loop {
+            self.ready().await?;
+            let mut running = self.running.lock().unwrap();
+            if running.is_none() {
+                let kill = Arc::new(AtomicBool::new(false));
+                let wrote = Arc::new(AtomicBool::new(false));
+                *running = Some(Running {
+                    started: Instant::now(),
+                    kill: kill.clone(),
+                    wrote: wrote.clone(),
+                });
+                return Ok((kill, wrote));
+            }
+        }
+    }
+
+    fn kill(&self) -> Frame {
+        match &*self.running.lock().unwrap() {
+            None => Frame::Error("NOTBUSY No scripts in execution right now.".to_string()),
+            Some(running) if running.started.elapsed() < self.time_limit => Frame::Error(format!(
+                "ERR The script can be killed after running for {} ms",
+                self.time_limit.as_millis()
+            )),
+            Some(running) if running.wrote.load(Ordering::SeqCst) => Frame::Error(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset."
+                    .to_string(),
+            ),
+            Some(running) => {
+                running.kill.store(true, Ordering::SeqCst);
+                Frame::Simple("OK".to_string())
+            }
+        }
+    }
+}
+
+/// `SCRIPT LOAD|EXISTS|FLUSH|KILL`
+pub fn script(scripts: &Scripts, args: &[Bytes]) -> Frame {
+    let subcommand = match args.get(1) {
+        Some(arg) => String::from_utf8_lossy(arg).to_uppercase(),
+        None => return cmd::wrong_arity("script"),
+    };
+    match (subcommand.as_str(), args.len()) {
+        ("LOAD", 3) => Frame::bulk(&scripts.load(args[2].clone())),
+        ("EXISTS", n) if n > 2 => {
+            let cache = scripts.cache.lock().unwrap();
+            Frame::Array(
+                args[2..]
+                    .iter()
+                    .map(|sha| {
+                        let sha = String::from_utf8_lossy(sha).to_lowercase();
+                        Frame::Integer(cache.contains_key(&sha) as i64)
+                    })
+                    .collect(),
+            )
+        }
+        ("FLUSH", 2) | ("FLUSH", 3) => {
+            scripts.cache.lock().unwrap().clear();
+            Frame::Simple("OK".to_string())
+        }
+        ("KILL", 2) => scripts.kill(),
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand.to_lowercase()
+        )),
+    }
+}
+
+/// Keys of `EVAL script numkeys key [key ...] arg [arg ...]`.
+pub fn keys(args: &[Bytes]) -> &[Bytes] {
+    match args.get(2).and_then(cmd::int_arg) {
+        Some(n) if n >= 0 && 3 + n as usize <= args.len() => &args[3..3 + n as usize],
+        _ => &[],
+    }
+}
+
+/// `EVAL script numkeys ...` and `EVALSHA sha1 numkeys ...`. The script runs
+/// on the blocking pool while holding the store lock.
+pub async fn eval(db: &Db, scripts: &Arc<Scripts>, args: &[Bytes], asking: bool) -> Frame {
+    let name = cmd::name(args);
+    if args.len() < 3 {
+        return cmd::wrong_arity(&name);
+    }
+    let body = if name == "EVALSHA" {
+        let sha = String::from_utf8_lossy(&args[1]).to_lowercase();
+        match scripts.cache.lock().unwrap().get(&sha) {
+            Some(body) => body.clone(),
+            None => {
+                return Frame::Error("NOSCRIPT No matching script. Please use EVAL.".to_string())
+            }
+        }
+    } else {
+        scripts.load(args[1].clone());
+        args[1].clone()
+    };
+    let numkeys = match cmd::int_arg(&args[2]) {
+        Some(n) if n >= 0 && 3 + n as usize <= args.len() => n as usize,
+        Some(_) => {
+            return Frame::Error(
+                "ERR Number of keys can't be greater than number of args".to_string(),
+            )
+        }
+        None => return Frame::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    let db = db.clone();
+    let scripts = scripts.clone();
+    let args = args.to_vec();
+    let (kill, wrote) = match scripts.start().await {
+        Ok(started) => started,
+        Err(busy) => return busy,
+    };
+    let run = tokio::task::spawn_blocking(move || {
+        let mut state = db.lock().unwrap();
+        let result = match cluster::redirect(&state, &args, asking) {
+            Some(redirect) => redirect,
+            None => {
+                let keys = &args[3..3 + numkeys];
+                let argv = &args[3 + numkeys..];
+                run(&mut state, &body, keys, argv, kill, wrote)
+            }
+        };
+        // Commands wait on the marker rather than the store, so keep it until
+        // the store is let go.
+        drop(state);
+        *scripts.running.lock().unwrap() = None;
+        scripts.done.notify_waiters();
+        result
+    });
+    match run.await {
+        Ok(frame) => frame,
+        Err(e) => Frame::Error(format!("ERR script task failed: {}", e)),
+    }
+}
+
+fn run(
+    state: &mut State,
+    body: &[u8],
+    keys: &[Bytes],
+    argv: &[Bytes],
+    kill: Arc<AtomicBool>,
+    wrote: Arc<AtomicBool>,
+) -> Frame {
+    // Scripts only get the pure libraries, no io or os access.
+    let lua = match Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    ) {
+        Ok(lua) => lua,
+        Err(e) => return Frame::Error(format!("ERR {}", e)),
+    };
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(1000),
+        move |_, _| match kill.load(Ordering::SeqCst) {
+            true => Err(mlua::Error::RuntimeError(KILLED.to_string())),
+            false => Ok(()),
+        },
+    );
+
+    let state = RefCell::new(state);
+    let call = |args: Variadic<Value>| -> mlua::Result<Frame> {
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                Value::String(s) => Ok(Bytes::copy_from_slice(s.as_bytes())),
+                Value::Integer(n) => Ok(Bytes::from(n.to_string())),
+                Value::Number(n) => Ok(Bytes::from((*n as i64).to_string())),
+                _ => Err(mlua::Error::RuntimeError(
+                    "ERR Lua redis lib command arguments must be strings or integers".to_string(),
+                )),
+            })
+            .collect::<mlua::Result<Vec<Bytes>>>()?;
+        if args.is_empty() {
+            return Err(mlua::Error::RuntimeError(
+                "ERR Please specify at least one argument for this redis lib call".to_string(),
+            ));
+        }
+        if cmd::is_write(&cmd::name(&args)) {
+            wrote.store(true, Ordering::SeqCst);
+        }
+        Ok(cmd::execute(&mut state.borrow_mut(), &args))
+    };
+    let call = &call;
+    let result = lua.scope(|scope| {
+        let globals = lua.globals();
+        globals.set("KEYS", strings(&lua, keys)?)?;
+        globals.set("ARGV", strings(&lua, argv)?)?;
+
+        let redis = lua.create_table()?;
+        redis.set(
+            "call",
+            scope.create_function(move |lua, args: Variadic<Value>| match call(args)? {
+                Frame::Error(e) => Err(mlua::Error::RuntimeError(e)),
+                frame => to_lua(lua, frame),
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(move |lua, args: Variadic<Value>| match call(args) {
+                Ok(frame) => to_lua(lua, frame),
+                Err(e) => to_lua(lua, Frame::Error(message(&e))),
+            })?,
+        )?;
+        redis.set(
+            "status_reply",
+            lua.create_function(|lua, s: mlua::String| {
+                let reply = lua.create_table()?;
+                reply.set("ok", s)?;
+                Ok(reply)
+            })?,
+        )?;
+        redis.set(
+            "error_reply",
+            lua.create_function(|lua, s: mlua::String| {
+                let reply = lua.create_table()?;
+                reply.set("err", s)?;
+                Ok(reply)
+            })?,
+        )?;
+        globals.set("redis", redis)?;
+
+        let value = lua.load(body).set_name("@user_script").eval::<Value>()?;
+        Ok(from_lua(value))
+    });
+    match result {
+        Ok(frame) => frame,
+        Err(e) => {
+            // Errors from redis.call and SCRIPT KILL already carry a code.
+            let msg = message(&e);
+            match msg.split_once(' ') {
+                Some((code, _)) if code.bytes().all(|b| b.is_ascii_uppercase()) => {
+                    Frame::Error(msg)
+                }
+                _ => Frame::Error(format!("ERR Error running script: {}", msg)),
+            }
+        }
+    }
+}
+
+/// The innermost message of an error raised from a callback or the hook.
+fn message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => message(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        e => e.to_string(),
+    }
+}
+
+fn strings<'lua>(lua: &'lua Lua, items: &[Bytes]) -> mlua::Result<mlua::Table<'lua>> {
+    let table = lua.create_table_with_capacity(items.len(), 0)?;
+    for (i, item) in items.iter().enumerate() {
+        table.set(i + 1, lua.create_string(&item[..])?)?;
+    }
+    Ok(table)
+}
+
+/// Convert a reply to Lua following the Redis conventions: nil replies
+/// become `false`, status and error replies become `{ok=...}` and `{err=...}`.
+fn to_lua<'lua>(lua: &'lua Lua, frame: Frame) -> mlua::Result<Value<'lua>> {
+    Ok(match frame {
+        Frame::Integer(n) => Value::Integer(n),
+        Frame::Bulk(data) => Value::String(lua.create_string(&data[..])?),
+        Frame::Simple(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s)?;
+            Value::Table(table)
+        }
+        Frame::Error(e) => {
+            let table = lua.create_table()?;
+            table.set("err", e)?;
+            Value::Table(table)
+        }
+        Frame::Null => Value::Boolean(false),
+        Frame::Boolean(b) => Value::Boolean(b),
+        Frame::Double(n) => Value::Number(n),
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        Frame::Map(entries) => {
+            let table = lua.create_table()?;
+            for (key, value) in entries {
+                table.set(to_lua(lua, key)?, to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        Frame::BigNumber(n) => Value::String(lua.create_string(&n)?),
+        Frame::Verbatim { data, .. } => Value::String(lua.create_string(&data[..])?),
+    })
+}
+
+/// Convert a script's return value to a reply. Numbers are truncated to
+/// integers and arrays stop at the first nil, as in Redis.
+fn from_lua(value: Value) -> Frame {
+    match value {
+        Value::Nil => Frame::Null,
+        Value::Boolean(true) => Frame::Integer(1),
+        Value::Boolean(false) => Frame::Null,
+        Value::Integer(n) => Frame::Integer(n),
+        Value::Number(n) => Frame::Integer(n as i64),
+        Value::String(s) => Frame::Bulk(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(table) => {
+            if let Ok(mlua::Value::String(e)) = table.raw_get("err") {
+                return Frame::Error(e.to_string_lossy().into_owned());
+            }
+            if let Ok(mlua::Value::String(s)) = table.raw_get("ok") {
+                return Frame::Simple(s.to_string_lossy().into_owned());
+            }
+            let mut items = vec![];
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(value) => items.push(from_lua(value)),
+                }
+            }
+            Frame::Array(items)
+        }
+        _ => Frame::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[tokio::test]
+    async fn busy_while_a_script_holds_the_store() {
+        let db = Db::default();
+        let scripts = Arc::new(Scripts::new(Duration::from_millis(100)));
+        let (locked, wait_locked) = mpsc::channel();
+        let script = {
+            let db = db.clone();
+            let scripts = scripts.clone();
+            thread::spawn(move || {
+                // Like eval, the script is marked running before it takes the
+                // store, and until after it lets go.
+                *scripts.running.lock().unwrap() = Some(Running {
+                    started: Instant::now(),
+                    kill: Arc::default(),
+                    wrote: Arc::default(),
+                });
+                let state = db.lock().unwrap();
+                locked.send(()).unwrap();
+                thread::sleep(Duration::from_secs(1));
+                drop(state);
+                *scripts.running.lock().unwrap() = None;
+                scripts.done.notify_waiters();
+            })
+        };
+        wait_locked.recv().unwrap();
+
+        let started = Instant::now();
+        match scripts.lock(&db).await {
+            Err(Frame::Error(e)) => assert!(e.starts_with("BUSY"), "{}", e),
+            _ => panic!("expected BUSY"),
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+        match scripts.start().await {
+            Err(Frame::Error(e)) => assert!(e.starts_with("BUSY"), "{}", e),
+            _ => panic!("expected BUSY"),
+        }
+        script.join().unwrap();
+        assert!(scripts.lock(&db).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn commands_wait_for_each_other() {
+        let db = Db::default();
+        let scripts = Scripts::new(Duration::from_millis(100));
+        let (locked, wait_locked) = mpsc::channel();
+        let command = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let _state = db.lock().unwrap();
+                locked.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            })
+        };
+        wait_locked.recv().unwrap();
+
+        // Past the time limit, but no script is running.
+        let started = Instant::now();
+        assert!(scripts.lock(&db).await.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        command.join().unwrap();
+    }
+}
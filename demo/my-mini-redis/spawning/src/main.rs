@@ -54,7 +54,7 @@ async fn process2(socket: TcpStream) {
                     Frame::Null
                 }
             }
-            cmd => panic!("unimplemented {:?}", cmd),
+            cmd => Frame::Error(format!("ERR unknown command {:?}", cmd)),
         };
         connection.write_frame(&response).await.unwrap();
     }
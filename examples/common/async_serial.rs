@@ -0,0 +1,237 @@
+//! Serial ports for tokio, shared by the relay and uart examples.
+//!
+//! The `serial` crate only offers blocking reads with a timeout. On unix this
+//! wraps the port's file descriptor in an `AsyncFd`, switched to non-blocking
+//! mode, and reads and writes it directly, so a port is just another source
+//! on the runtime. Elsewhere the port is read in blocking mode, returning to
+//! the runtime whenever its timeout expires without data, so keep that
+//! timeout short when several ports share a thread. Either way, timeouts of
+//! an exchange are up to the caller, with `tokio::time::timeout`.
+
+use crate::capture::{Capture, Direction};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(not(unix))]
+pub use self::blocking::AsyncSerial;
+#[cfg(unix)]
+pub use self::fd::AsyncSerial;
+
+#[cfg(unix)]
+mod fd {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::unix::AsyncFd;
+
+    pub struct AsyncSerial<T: AsRawFd> {
+        fd: AsyncFd<T>,
+    }
+
+    impl<T: AsRawFd> AsyncSerial<T> {
+        /// Take over an open port, or anything else with a file descriptor
+        /// like the master side of a pseudo-terminal. Must be called within a
+        /// runtime.
+        pub fn new(port: T) -> io::Result<AsyncSerial<T>> {
+            let fd = port.as_raw_fd();
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(AsyncSerial {
+                fd: AsyncFd::new(port)?,
+            })
+        }
+
+        pub fn get_ref(&self) -> &T {
+            self.fd.get_ref()
+        }
+    }
+
+    fn syscall(n: isize) -> io::Result<usize> {
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    impl<T: AsRawFd> AsyncRead for AsyncSerial<T> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                let read = guard.try_io(|fd| {
+                    syscall(unsafe {
+                        libc::read(
+                            fd.as_raw_fd(),
+                            unfilled.as_mut_ptr() as *mut libc::c_void,
+                            unfilled.len(),
+                        )
+                    })
+                });
+                match read {
+                    Ok(Ok(n)) => {
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl<T: AsRawFd> AsyncWrite for AsyncSerial<T> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+                let written = guard.try_io(|fd| {
+                    syscall(unsafe {
+                        libc::write(
+                            fd.as_raw_fd(),
+                            buf.as_ptr() as *const libc::c_void,
+                            buf.len(),
+                        )
+                    })
+                });
+                match written {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod blocking {
+    use super::*;
+    use std::io::Read;
+
+    pub struct AsyncSerial<T> {
+        port: T,
+    }
+
+    impl<T: Read + Write> AsyncSerial<T> {
+        /// Take over an open port, whose timeout bounds how long a read or
+        /// write holds up the runtime.
+        pub fn new(port: T) -> io::Result<AsyncSerial<T>> {
+            Ok(AsyncSerial { port })
+        }
+
+        pub fn get_ref(&self) -> &T {
+            &self.port
+        }
+    }
+
+    /// Turn a port timeout into another turn for the other tasks.
+    fn pending<T>(result: io::Result<T>, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        match result {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    impl<T: Read + Unpin> AsyncRead for AsyncSerial<T> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = ready!(pending(
+                self.get_mut().port.read(buf.initialize_unfilled()),
+                cx
+            ))?;
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<T: Write + Unpin> AsyncWrite for AsyncSerial<T> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            pending(self.get_mut().port.write(buf), cx)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            pending(self.get_mut().port.flush(), cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+// Captures log async traffic the same way, the log itself is written
+// synchronously.
+impl<T: AsyncRead + Unpin, W: Write + Unpin> AsyncRead for Capture<T, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(this.get_mut()).poll_read(cx, buf))?;
+        let bytes = &buf.filled()[before..];
+        if !bytes.is_empty() {
+            this.record(Direction::Received, bytes)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin, W: Write + Unpin> AsyncWrite for Capture<T, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(this.get_mut()).poll_write(cx, buf))?;
+        if n > 0 {
+            this.record(Direction::Sent, &buf[..n])?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().get_mut()).poll_shutdown(cx)
+    }
+}
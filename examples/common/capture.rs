@@ -0,0 +1,111 @@
+//! Capture of the traffic on a serial port, shared by the relay and uart
+//! examples and read back by the inspect example.
+//!
+//! A capture file has one line per read or write that moved any bytes:
+//!
+//! ```text
+//! 2021-03-01T08:00:00.123456+01:00 > 55 01 12 00 00 00 03 6b
+//! 2021-03-01T08:00:00.141002+01:00 < 22 01 10 00 00 00 04 37
+//! ```
+//!
+//! The local timestamp with microseconds, `>` for bytes written to the
+//! device or `<` for bytes read from it, and the bytes in hex.
+
+use chrono::Local;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: String,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Wraps a port, logging every byte that goes through it to `log`.
+pub struct Capture<T, W = File> {
+    inner: T,
+    log: W,
+}
+
+impl<T> Capture<T> {
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> io::Result<Capture<T>> {
+        Ok(Capture::new(inner, File::create(path)?))
+    }
+}
+
+impl<T, W: Write> Capture<T, W> {
+    pub fn new(inner: T, log: W) -> Capture<T, W> {
+        Capture { inner, log }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Log `bytes` as moved in `direction` now.
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let mut line = Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string();
+        line.push_str(match direction {
+            Direction::Sent => " >",
+            Direction::Received => " <",
+        });
+        for byte in bytes {
+            let _ = write!(line, " {:02x}", byte);
+        }
+        line.push('\n');
+        // One write per line, so an interrupted capture only loses the end.
+        self.log.write_all(line.as_bytes())
+    }
+}
+
+impl<T: Read, W: Write> Read for Capture<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.record(Direction::Received, &buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write, W: Write> Write for Capture<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.record(Direction::Sent, &buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Parse a line of a capture file.
+pub fn parse_line(line: &str) -> Option<Record> {
+    let mut fields = line.split_whitespace();
+    let timestamp = fields.next()?.to_string();
+    let direction = match fields.next()? {
+        ">" => Direction::Sent,
+        "<" => Direction::Received,
+        _ => return None,
+    };
+    let bytes = fields
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Record {
+        timestamp,
+        direction,
+        bytes,
+    })
+}
@@ -0,0 +1,497 @@
+//! Decode a capture written by `relay --capture` or `uart --capture`,
+//! printing every frame or message with its timestamp and direction and
+//! flagging checksum and framing errors.
+//!
+//! Both protocols are decoded with the codecs of their examples.
+//!
+//! `replay` writes the bytes of one direction of a capture to a port, or the
+//! slave side of a pty, keeping the gaps between them: the sent bytes to
+//! drive a device again, the received ones to stand in for it.
+
+#[path = "../common/capture.rs"]
+#[allow(dead_code)]
+mod capture;
+#[path = "../uart/codec.rs"]
+#[allow(dead_code)]
+mod codec;
+#[path = "../relay/error.rs"]
+#[allow(dead_code)]
+mod error;
+#[path = "../relay/frame.rs"]
+#[allow(dead_code)]
+mod frame;
+
+use capture::{Direction, Record};
+use chrono::DateTime;
+use codec::Decoder;
+use frame::Frame;
+use serial::SerialPort;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+const USAGE: &str = "Usage: inspect <relay|uart> <capture file>
+       inspect replay [--received] [--baud <rate>] <capture file> <port>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Relay,
+    Uart,
+}
+
+/// The bytes of one direction waiting for the rest of their frame.
+enum Stream {
+    Relay(Vec<u8>),
+    Uart(Decoder),
+}
+
+impl Stream {
+    fn new(protocol: Protocol) -> Stream {
+        match protocol {
+            Protocol::Relay => Stream::Relay(vec![]),
+            Protocol::Uart => Stream::Uart(Decoder::default()),
+        }
+    }
+
+    /// Decode what `bytes` complete, a line per frame or error.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Result<String, String>> {
+        let mut decoded = vec![];
+        match self {
+            Stream::Relay(buffer) => {
+                buffer.extend_from_slice(bytes);
+                loop {
+                    let skipped = frame::resync(buffer);
+                    if skipped > 0 {
+                        decoded.push(Err(format!("framing: skipped {} bytes", skipped)));
+                    }
+                    match frame::next(buffer) {
+                        Some(Ok(frame)) => decoded.push(Ok(describe(&frame))),
+                        Some(Err(e)) => decoded.push(Err(e.to_string())),
+                        None => break,
+                    }
+                }
+            }
+            Stream::Uart(decoder) => {
+                decoder.push(bytes);
+                for message in decoder {
+                    decoded.push(match message {
+                        Ok(message) => Ok(format!(
+                            "message {} bytes: {:?}",
+                            message.len(),
+                            String::from_utf8_lossy(&message)
+                        )),
+                        Err(e) => Err(format!("framing: {}", e)),
+                    });
+                }
+            }
+        }
+        decoded
+    }
+
+    fn pending(&self) -> usize {
+        match self {
+            Stream::Relay(buffer) => buffer.len(),
+            Stream::Uart(decoder) => decoder.pending(),
+        }
+    }
+}
+
+struct Inspector {
+    sent: Stream,
+    received: Stream,
+    lines: Vec<String>,
+    errors: usize,
+}
+
+impl Inspector {
+    fn new(protocol: Protocol) -> Inspector {
+        Inspector {
+            sent: Stream::new(protocol),
+            received: Stream::new(protocol),
+            lines: vec![],
+            errors: 0,
+        }
+    }
+
+    fn error(&mut self, prefix: &str, msg: String) {
+        self.errors += 1;
+        self.lines.push(format!("{} ERROR {}", prefix, msg));
+    }
+
+    fn record(&mut self, record: &Record) {
+        let (arrow, stream) = match record.direction {
+            Direction::Sent => (">", &mut self.sent),
+            Direction::Received => ("<", &mut self.received),
+        };
+        let prefix = format!("{} {}", record.timestamp, arrow);
+        for line in stream.push(&record.bytes) {
+            match line {
+                Ok(line) => self.lines.push(format!("{} {}", prefix, line)),
+                Err(msg) => self.error(&prefix, msg),
+            }
+        }
+    }
+
+    /// Flag what is left over at the end of the capture.
+    fn finish(&mut self) {
+        for (arrow, left) in &[(">", self.sent.pending()), ("<", self.received.pending())] {
+            if *left > 0 {
+                self.error(
+                    &format!("end {}", arrow),
+                    format!("framing: capture ends inside a frame, {} bytes left", left),
+                );
+            }
+        }
+    }
+}
+
+fn describe(frame: &Frame) -> String {
+    let kind = match frame.header {
+        frame::DATA_HEADER => "request",
+        _ => "reply",
+    };
+    let function = match frame.function {
+        frame::FUNC_READ_STATUS => "status",
+        frame::FUNC_OFF_ONE => "off",
+        frame::FUNC_ON_ONE => "on",
+        frame::FUNC_FLIP_ONE => "flip",
+        frame::FUNC_SET_MASK => "set mask",
+        _ => "unknown function",
+    };
+    let value = match frame.function {
+        frame::FUNC_OFF_ONE | frame::FUNC_ON_ONE | frame::FUNC_FLIP_ONE => {
+            format!("channel {}", frame.value)
+        }
+        _ => format!("{:#010x}", frame.value),
+    };
+    format!(
+        "{} board {} {} ({:#04x}) {}",
+        kind, frame.address, function, frame.function, value
+    )
+}
+
+/// Decode the capture in `text`, returning the report and the number of
+/// errors in it.
+fn inspect(protocol: Protocol, text: &str) -> (Vec<String>, usize) {
+    let mut inspector = Inspector::new(protocol);
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match capture::parse_line(line) {
+            Some(record) => inspector.record(&record),
+            None => inspector.error(
+                &format!("line {}", i + 1),
+                "not a capture record".to_string(),
+            ),
+        }
+    }
+    inspector.finish();
+    (inspector.lines, inspector.errors)
+}
+
+/// Options of `replay`.
+#[derive(Debug, PartialEq)]
+struct Replay {
+    direction: Direction,
+    baud: usize,
+    capture: String,
+    port: String,
+}
+
+impl Replay {
+    fn new(args: &[String]) -> Result<Replay, String> {
+        let mut direction = Direction::Sent;
+        let mut baud = 9600;
+        let mut paths = vec![];
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--received" => direction = Direction::Received,
+                "--baud" => {
+                    baud = match iter.next().map(|value| value.parse()) {
+                        Some(Ok(baud)) if baud > 0 => baud,
+                        _ => return Err("--baud takes a rate like 9600".to_string()),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => paths.push(arg.clone()),
+            }
+        }
+        match &paths[..] {
+            [capture, port] => Ok(Replay {
+                direction,
+                baud,
+                capture: capture.clone(),
+                port: port.clone(),
+            }),
+            _ => Err("replay takes a capture file and a port".to_string()),
+        }
+    }
+}
+
+/// The bytes of `direction` in the capture `text`, each with its time since
+/// the first of them.
+fn timeline(text: &str, direction: Direction) -> Result<Vec<(Duration, Vec<u8>)>, String> {
+    let mut start = None;
+    let mut timeline = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = capture::parse_line(line)
+            .ok_or_else(|| format!("line {}: not a capture record", i + 1))?;
+        if record.direction != direction {
+            continue;
+        }
+        let time = DateTime::parse_from_rfc3339(&record.timestamp)
+            .map_err(|e| format!("line {}: {}: {}", i + 1, record.timestamp, e))?;
+        let start = *start.get_or_insert(time);
+        // A clock set back during the capture doesn't rewind the replay.
+        let offset = (time - start).to_std().unwrap_or_default();
+        timeline.push((offset, record.bytes));
+    }
+    Ok(timeline)
+}
+
+/// Write the bytes of `timeline` to `port`, each once its time has come,
+/// counting from now. Returns the number of bytes written.
+fn replay<W: Write>(timeline: &[(Duration, Vec<u8>)], port: &mut W) -> io::Result<usize> {
+    let start = Instant::now();
+    let mut written = 0;
+    for (offset, bytes) in timeline {
+        if let Some(wait) = offset.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+        port.write_all(bytes)?;
+        port.flush()?;
+        written += bytes.len();
+    }
+    Ok(written)
+}
+
+fn run_replay(options: &Replay) -> Result<(), String> {
+    let text =
+        fs::read_to_string(&options.capture).map_err(|e| format!("{}: {}", options.capture, e))?;
+    let timeline = timeline(&text, options.direction)?;
+    let opened = serial::open(&options.port).and_then(|mut port| {
+        port.reconfigure(&|settings| {
+            settings.set_baud_rate(serial::BaudRate::from_speed(options.baud))
+        })?;
+        Ok(port)
+    });
+    let mut port = opened.map_err(|e| format!("{}: {}", options.port, e))?;
+    let written = replay(&timeline, &mut port).map_err(|e| format!("{}: {}", options.port, e))?;
+    eprintln!(
+        "inspect: replayed {} bytes in {} writes",
+        written,
+        timeline.len()
+    );
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.first().map(String::as_str) == Some("replay") {
+        let result = Replay::new(&args[1..]).and_then(|options| run_replay(&options));
+        if let Err(e) = result {
+            eprintln!("inspect: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+    let (protocol, path) = match &args[..] {
+        [protocol, path] if protocol == "relay" => (Protocol::Relay, path),
+        [protocol, path] if protocol == "uart" => (Protocol::Uart, path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("inspect: {}: {}", path, e);
+            process::exit(2);
+        }
+    };
+    let (lines, errors) = inspect(protocol, &text);
+    for line in lines {
+        println!("{}", line);
+    }
+    if errors > 0 {
+        eprintln!("inspect: {} errors", errors);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture::Capture;
+    use std::io::Read;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!(" {:02x}", b)).collect()
+    }
+
+    #[test]
+    fn relay_frames() {
+        let on = Frame::request(1, frame::FUNC_ON_ONE, 3).encode();
+        let status = Frame::reply(1, frame::FUNC_READ_STATUS, 4).encode();
+        let mut corrupt = status;
+        corrupt[7] ^= 1;
+        let text = format!(
+            "t1 >{}\nt2 <{}\nt3 <{}\nt4 <{}\nt5 < 00 01{}\nt6 > 55 01\n",
+            hex(&on),
+            hex(&status[..3]),
+            hex(&status[3..]),
+            hex(&corrupt),
+            hex(&status)
+        );
+        let (lines, errors) = inspect(Protocol::Relay, &text);
+        assert_eq!(
+            vec![
+                "t1 > request board 1 on (0x12) channel 3",
+                "t3 < reply board 1 status (0x10) 0x00000004",
+                "t4 < ERROR bad checksum 0x36 in reply, expected 0x37",
+                "t5 < ERROR framing: skipped 2 bytes",
+                "t5 < reply board 1 status (0x10) 0x00000004",
+                "end > ERROR framing: capture ends inside a frame, 2 bytes left",
+            ],
+            lines
+        );
+        assert_eq!(3, errors);
+    }
+
+    #[test]
+    fn uart_messages() {
+        let text = "t1 < 00 00 00 02 68\n\
+                    t2 < 69 00 00 00 00\n\
+                    t3 > ff ff ff ff 00 00 00 01 21\n\
+                    oops\n";
+        let (lines, errors) = inspect(Protocol::Uart, text);
+        assert_eq!(
+            vec![
+                "t2 < message 2 bytes: \"hi\"",
+                "t2 < message 0 bytes: \"\"",
+                "t3 > ERROR framing: skipped 4 bytes, from a frame length of 4294967295 over the limit",
+                "t3 > message 1 bytes: \"!\"",
+                "line 4 ERROR not a capture record",
+            ],
+            lines
+        );
+        assert_eq!(2, errors);
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let mut log = vec![];
+        Capture::new(vec![], &mut log)
+            .write_all(&[0x55, 0xff])
+            .unwrap();
+        let mut port = Capture::new(&[0x22u8, 0x01][..], &mut log);
+        let mut buf = [0u8; 4];
+        assert_eq!(2, port.read(&mut buf).unwrap());
+        assert_eq!(0, port.read(&mut buf).unwrap());
+
+        let text = String::from_utf8(log).unwrap();
+        let records = text
+            .lines()
+            .map(|line| capture::parse_line(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, records.len());
+        assert_eq!(
+            (Direction::Sent, vec![0x55, 0xff]),
+            (records[0].direction, records[0].bytes.clone())
+        );
+        assert_eq!(
+            (Direction::Received, vec![0x22, 0x01]),
+            (records[1].direction, records[1].bytes.clone())
+        );
+        assert_eq!(None, capture::parse_line("t1 > zz"));
+        assert_eq!(None, capture::parse_line("t1 ? 55"));
+    }
+
+    #[test]
+    fn replay_options() {
+        let args = |line: &str| {
+            line.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            Ok(Replay {
+                direction: Direction::Sent,
+                baud: 9600,
+                capture: "wire.log".to_string(),
+                port: "/dev/pts/3".to_string(),
+            }),
+            Replay::new(&args("wire.log /dev/pts/3"))
+        );
+        let options = Replay::new(&args("--received wire.log --baud 115200 COM3")).unwrap();
+        assert_eq!(
+            (Direction::Received, 115200),
+            (options.direction, options.baud)
+        );
+        for bad in &[
+            "wire.log",
+            "a b c",
+            "--baud 0 a b",
+            "--baud a b",
+            "--fast a b",
+        ] {
+            assert!(Replay::new(&args(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn replay_timing() {
+        let text = "2021-03-01T08:00:00.100000+01:00 > 55 01\n\
+                    2021-03-01T08:00:00.120000+01:00 < 22\n\
+                    2021-03-01T08:00:00.150000+01:00 > 12\n\
+                    2021-03-01T08:00:00.140000+01:00 > 6b\n";
+        let sent = timeline(text, Direction::Sent).unwrap();
+        assert_eq!(
+            vec![
+                (Duration::from_millis(0), vec![0x55, 0x01]),
+                (Duration::from_millis(50), vec![0x12]),
+                (Duration::from_millis(40), vec![0x6b]),
+            ],
+            sent
+        );
+        assert_eq!(
+            vec![(Duration::from_millis(0), vec![0x22])],
+            timeline(text, Direction::Received).unwrap()
+        );
+        assert!(timeline("t1 > 55", Direction::Sent)
+            .unwrap_err()
+            .starts_with("line 1: t1"));
+        assert_eq!(
+            Err("line 2: not a capture record".to_string()),
+            timeline("\noops", Direction::Sent)
+        );
+
+        /// Remembers when each write arrived.
+        struct Port(Instant, Vec<(Duration, Vec<u8>)>);
+        impl Write for Port {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.1.push((self.0.elapsed(), buf.to_vec()));
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut port = Port(Instant::now(), vec![]);
+        assert_eq!(4, replay(&sent, &mut port).unwrap());
+        let writes = port.1;
+        assert_eq!(
+            vec![vec![0x55, 0x01], vec![0x12], vec![0x6b]],
+            writes.iter().map(|w| w.1.clone()).collect::<Vec<_>>()
+        );
+        assert!(writes[1].0 >= Duration::from_millis(50));
+        // Bytes already due go out right after the previous ones.
+        assert!(writes[2].0 - writes[1].0 < Duration::from_millis(30));
+    }
+}
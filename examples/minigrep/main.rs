@@ -1,12 +1,19 @@
-use std::env;
+use std::{env, process};
 
 mod minigrep;
 
 use minigrep::Config;
 
 fn main() {
-    if let Err(e) = minigrep::run(Config::new(&env::args().collect::<Vec<String>>()).expect("err"))
-    {
-        println!("{}", e)
+    let config = Config::new(&env::args().collect::<Vec<String>>()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2)
+    });
+    match minigrep::run(config) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("minigrep: {}", e);
+            process::exit(2)
+        }
     }
 }
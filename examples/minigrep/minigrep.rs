@@ -1,47 +1,757 @@
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
 use std::error::Error;
-use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(config.filename)?;
-    let vec = search(&config.query, &content);
-    if vec.len() > 0 {
-        for v in vec {
-            println!("{}", v)
+pub const USAGE: &str = "usage: minigrep [-iwvFEncl] [-A|-B|-C <lines>] [--json] \
+    [--include <glob>] [--exclude <glob>] <query> [path]";
+
+/// The name of the input when there is no path, or it is `-`.
+const STDIN: &str = "(standard input)";
+
+// Colours of the parts of a line, grep's defaults.
+const MATCH: &str = "01;31";
+const PATH: &str = "35";
+const LINE_NUMBER: &str = "32";
+const SEPARATOR: &str = "36";
+
+/// Search and print the results, returning the exit code like grep: 0 when a
+/// line was selected, 1 when none was, and 2 when a file couldn't be read,
+/// whether or not there were matches elsewhere.
+pub fn run(config: Config) -> Result<i32, Box<dyn Error>> {
+    let start = Instant::now();
+    let matcher = Matcher::new(&config.query, &config.options)?;
+    let mut output = config.output.clone();
+    output.color = !output.json && io::stdout().is_terminal();
+    let stdout = io::stdout();
+    let print = |line: String| writeln!(stdout.lock(), "{}", line);
+    // A directory may hold any number of files, name them all. Each file's
+    // output streams on its turn, files searched ahead of it hold theirs back.
+    output.path = config.path != "-" && Path::new(&config.path).is_dir();
+    let mut summary = Summary::default();
+    let mut failed = false;
+    if !output.path {
+        let searched = if config.path == "-" {
+            let stdin = io::stdin();
+            search(&matcher, &output, STDIN, stdin.lock(), print)
+        } else {
+            File::open(&config.path)
+                .map(BufReader::new)
+                .and_then(|mut file| {
+                    if is_binary(&mut file)? {
+                        return Ok(0);
+                    }
+                    search(&matcher, &output, &config.path, file, print)
+                })
+        };
+        match searched {
+            Ok(selected) => summary.add(selected),
+            Err(e) => {
+                failed = true;
+                let name = if config.path == "-" {
+                    STDIN
+                } else {
+                    &config.path
+                };
+                eprintln!("minigrep: {}: {}", name, e);
+            }
         }
     } else {
-        println!("no found");
+        let (files, errors) = files(&config)?;
+        for e in &errors {
+            eprintln!("minigrep: {}", e);
+        }
+        failed = !errors.is_empty();
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut printed = false;
+        // The first error writing stdout, after which the rest is dropped.
+        let mut broken = None;
+        // Whether the file being emitted printed anything yet.
+        let mut started = false;
+        search_files(&matcher, &output, &files, workers, |path, event| {
+            let line = match event {
+                Event::Line(line) => line,
+                Event::Done(selected) => {
+                    started = false;
+                    match selected {
+                        Ok(selected) => summary.add(selected),
+                        Err(e) => {
+                            failed = true;
+                            eprintln!("minigrep: {}: {}", path.display(), e);
+                        }
+                    }
+                    return;
+                }
+            };
+            if broken.is_some() {
+                return;
+            }
+            if !started && output.context() && !output.json && printed {
+                broken = print(paint(output.color, SEPARATOR, "--")).err();
+            }
+            started = true;
+            printed = true;
+            if broken.is_none() {
+                broken = print(line).err();
+            }
+        });
+        if let Some(e) = broken {
+            return Err(e.into());
+        }
+    }
+    if output.json {
+        print(
+            Record::Summary {
+                files: summary.files,
+                files_with_matches: summary.files_with_matches,
+                matched_lines: summary.matched_lines,
+                elapsed_secs: start.elapsed().as_secs_f64(),
+            }
+            .to_json(),
+        )?;
     }
-    Ok(())
+    Ok(if failed {
+        2
+    } else if summary.matched_lines > 0 {
+        0
+    } else {
+        1
+    })
+}
+
+/// Totals over the files searched.
+#[derive(Debug, Default)]
+struct Summary {
+    files: usize,
+    files_with_matches: usize,
+    matched_lines: usize,
+}
+
+impl Summary {
+    fn add(&mut self, selected: usize) {
+        self.files += 1;
+        self.files_with_matches += (selected > 0) as usize;
+        self.matched_lines += selected;
+    }
+}
+
+/// One line of `--json` output. A file's records start with `begin` and end
+/// with `end`, with a `match` for every selected line and a `context` for
+/// every line printed around them. The `summary` comes last.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record<'a> {
+    Begin {
+        path: &'a str,
+    },
+    Match {
+        path: &'a str,
+        line_number: usize,
+        /// Offset of the start of the line in the input.
+        byte_offset: u64,
+        text: &'a str,
+        submatches: Vec<Submatch<'a>>,
+    },
+    Context {
+        path: &'a str,
+        line_number: usize,
+        byte_offset: u64,
+        text: &'a str,
+    },
+    End {
+        path: &'a str,
+        matched_lines: usize,
+        elapsed_secs: f64,
+    },
+    Summary {
+        files: usize,
+        files_with_matches: usize,
+        matched_lines: usize,
+        elapsed_secs: f64,
+    },
+}
+
+/// A match within a line, `start` and `end` being byte offsets into `text`
+/// of the line.
+#[derive(Debug, Serialize)]
+struct Submatch<'a> {
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+impl Record<'_> {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("records are plain data")
+    }
+}
+
+/// What searching the files hands to `emit`.
+#[derive(Debug)]
+enum Event {
+    /// A line of output for the file, ready to print.
+    Line(String),
+    /// The file is done, with the number of selected lines.
+    Done(io::Result<usize>),
 }
 
-fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let mut v: Vec<&'a str> = vec![];
-    for line in contents.lines() {
-        if line.contains(query) {
-            v.push(line)
+/// Output a file may hold back while the files before it are searched, past
+/// which its worker waits for its turn.
+const HELD_PER_FILE: usize = 1024 * 1024;
+
+/// Search `files` on `workers` threads, handing each file's output to `emit`
+/// in the order of `files`. The file whose turn it is streams its lines as
+/// they are found, the output of one file is never interleaved with
+/// another's. Workers stay within `2 * workers` files of the one streaming,
+/// and each of those holds back at most `HELD_PER_FILE` bytes before waiting
+/// for its turn.
+fn search_files<F>(
+    matcher: &Matcher,
+    output: &Output,
+    files: &[PathBuf],
+    workers: usize,
+    mut emit: F,
+) where
+    F: FnMut(&Path, Event),
+{
+    let workers = workers.clamp(1, files.len().max(1));
+    let window = 2 * workers;
+    let next = AtomicUsize::new(0);
+    // The file whose turn it is, and its changes.
+    let emitted = (Mutex::new(0), Condvar::new());
+    let wait = |until: &dyn Fn(usize) -> bool| {
+        let mut turn = emitted.0.lock().unwrap();
+        while !until(*turn) {
+            turn = emitted.1.wait(turn).unwrap();
+        }
+    };
+    // Bounded, so a worker can't run ahead of stdout.
+    let (sender, receiver) = mpsc::sync_channel(64 * workers);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let next = &next;
+            let wait = &wait;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let path = match files.get(index) {
+                    Some(path) => path,
+                    None => break,
+                };
+                wait(&|turn| index < turn + window);
+                let mut held = 0;
+                let selected = search_file(matcher, output, path, |line| {
+                    if held > HELD_PER_FILE {
+                        wait(&|turn| index <= turn);
+                        held = 0;
+                    }
+                    held += line.len();
+                    sender
+                        .send((index, Event::Line(line)))
+                        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+                });
+                if sender.send((index, Event::Done(selected))).is_err() {
+                    break;
+                }
+            });
         }
+        drop(sender);
+
+        // The output of files ahead of their turn waits here until their
+        // predecessors are done.
+        let mut held: BTreeMap<usize, Vec<Event>> = BTreeMap::new();
+        let mut turn = 0;
+        for (index, event) in receiver {
+            if index != turn {
+                held.entry(index).or_default().push(event);
+                continue;
+            }
+            let mut done = matches!(event, Event::Done(_));
+            emit(&files[turn], event);
+            while done {
+                turn += 1;
+                *emitted.0.lock().unwrap() = turn;
+                emitted.1.notify_all();
+                done = false;
+                for event in held.remove(&turn).unwrap_or_default() {
+                    done = matches!(event, Event::Done(_));
+                    emit(&files[turn], event);
+                }
+            }
+        }
+    });
+}
+
+/// Search one file, handing its output to `emit` and reporting nothing if it
+/// is binary.
+fn search_file<F>(matcher: &Matcher, output: &Output, path: &Path, emit: F) -> io::Result<usize>
+where
+    F: FnMut(String) -> io::Result<()>,
+{
+    let mut file = BufReader::new(File::open(path)?);
+    if is_binary(&mut file)? {
+        return Ok(0);
+    }
+    search(matcher, output, &path.display().to_string(), file, emit)
+}
+
+/// Whether `reader` is binary, taken to be so when its first block holds a
+/// NUL byte. Nothing is consumed.
+fn is_binary<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.contains(&0))
+}
+
+/// Search `reader` a line at a time, handing the output for the lines
+/// `matcher` selects to `emit` as `output` asks, and return the number of
+/// selected lines. Besides the line being read, only the lines kept for
+/// context before a match are held, so a reader of any size is searched in
+/// the same memory; what becomes of the output is up to `emit`. Invalid UTF-8
+/// is replaced rather than an error. `name` prefixes lines and is printed by
+/// `-l`.
+fn search<R, F>(
+    matcher: &Matcher,
+    output: &Output,
+    name: &str,
+    mut reader: R,
+    mut emit: F,
+) -> io::Result<usize>
+where
+    R: BufRead,
+    F: FnMut(String) -> io::Result<()>,
+{
+    let start = Instant::now();
+    let path = name;
+    let name = paint(output.color, PATH, name);
+    // Selected lines are separated from their context by ':' and '-'.
+    let format = |number: usize, offset: u64, text: &str, selected: bool| {
+        if output.json {
+            let line_number = number + 1;
+            let byte_offset = offset;
+            return if selected {
+                let submatches = matcher
+                    .spans(text)
+                    .into_iter()
+                    .map(|span| Submatch {
+                        text: &text[span.clone()],
+                        start: span.start,
+                        end: span.end,
+                    })
+                    .collect();
+                Record::Match {
+                    path,
+                    line_number,
+                    byte_offset,
+                    text,
+                    submatches,
+                }
+                .to_json()
+            } else {
+                Record::Context {
+                    path,
+                    line_number,
+                    byte_offset,
+                    text,
+                }
+                .to_json()
+            };
+        }
+        let separator = paint(output.color, SEPARATOR, if selected { ":" } else { "-" });
+        let mut line = String::new();
+        if output.path {
+            line.push_str(&name);
+            line.push_str(&separator);
+        }
+        if output.line_numbers {
+            line.push_str(&paint(output.color, LINE_NUMBER, &(number + 1).to_string()));
+            line.push_str(&separator);
+        }
+        if selected && output.color {
+            line.push_str(&matcher.highlight(text));
+        } else {
+            line.push_str(text);
+        }
+        line
+    };
+    let print = !output.count && !output.files_only;
+    if output.json {
+        emit(Record::Begin { path }.to_json())?;
+    }
+    let mut selected = 0;
+    let mut buffer = vec![];
+    let mut offset = 0;
+    // Lines waiting to be printed as context before a match, with their
+    // index and offset.
+    let mut before: VecDeque<(usize, u64, String)> = VecDeque::with_capacity(output.before);
+    let mut after = 0;
+    // Index of the line after the last one printed.
+    let mut printed: Option<usize> = None;
+    for index in 0.. {
+        buffer.clear();
+        let read = reader.read_until(b'\n', &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let line_offset = offset;
+        offset += read as u64;
+        if buffer.ends_with(b"\n") {
+            buffer.pop();
+            if buffer.ends_with(b"\r") {
+                buffer.pop();
+            }
+        }
+        let text = String::from_utf8_lossy(&buffer);
+        if matcher.is_match(&text) {
+            selected += 1;
+            if output.files_only {
+                break;
+            }
+            if !print {
+                continue;
+            }
+            let from = before.front().map_or(index, |&(from, _, _)| from);
+            let gap = printed.is_some_and(|printed| from > printed);
+            if output.context() && !output.json && gap {
+                emit(paint(output.color, SEPARATOR, "--"))?;
+            }
+            for (context, offset, text) in before.drain(..) {
+                emit(format(context, offset, &text, false))?;
+            }
+            emit(format(index, line_offset, &text, true))?;
+            after = output.after;
+            printed = Some(index + 1);
+        } else if !print {
+        } else if after > 0 {
+            emit(format(index, line_offset, &text, false))?;
+            after -= 1;
+            printed = Some(index + 1);
+        } else if output.before > 0 {
+            if before.len() == output.before {
+                before.pop_front();
+            }
+            before.push_back((index, line_offset, text.into_owned()));
+        }
+    }
+    if output.json {
+        emit(
+            Record::End {
+                path,
+                matched_lines: selected,
+                elapsed_secs: start.elapsed().as_secs_f64(),
+            }
+            .to_json(),
+        )?;
+    } else if output.files_only {
+        if selected > 0 {
+            emit(name)?;
+        }
+    } else if output.count {
+        let separator = paint(output.color, SEPARATOR, ":");
+        emit(if output.path {
+            format!("{}{}{}", name, separator, selected)
+        } else {
+            selected.to_string()
+        })?;
+    }
+    Ok(selected)
+}
+
+fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The files under `config.path`, in name order. Like ripgrep this skips
+/// hidden files and whatever .gitignore and .ignore files exclude, in or out
+/// of a git repository, unless `--include` or `--exclude` decide on the file:
+/// the globs take precedence, though not inside a directory already skipped.
+/// A path naming a file is always searched. Entries that couldn't be read are
+/// returned separately, they shouldn't end the search.
+fn files(config: &Config) -> Result<(Vec<PathBuf>, Vec<ignore::Error>), ignore::Error> {
+    let mut overrides = OverrideBuilder::new(&config.path);
+    for glob in &config.include {
+        overrides.add(glob)?;
+    }
+    for glob in &config.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+    let walk = WalkBuilder::new(&config.path)
+        .require_git(false)
+        .overrides(overrides.build()?)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    let mut files = vec![];
+    let mut errors = vec![];
+    for entry in walk {
+        match entry {
+            Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                files.push(entry.into_path())
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+    Ok((files, errors))
+}
+
+/// How the query is matched against a line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// `-i`, or the `CASE_INSENSITIVE` environment variable.
+    pub ignore_case: bool,
+    /// `-w`: the match must not be part of a longer word.
+    pub whole_word: bool,
+    /// `-E` makes the query a regex, `-F` (the default) a plain string.
+    pub regex: bool,
+    /// `-v`: select the lines that don't match.
+    pub invert: bool,
+}
+
+/// The query compiled for `Options`. Fixed strings are escaped, so every mode
+/// goes through the same regex.
+pub struct Matcher {
+    regex: Regex,
+    whole_word: bool,
+    invert: bool,
+}
+
+impl Matcher {
+    pub fn new(query: &str, options: &Options) -> Result<Matcher, regex::Error> {
+        let pattern = if options.regex {
+            query.to_string()
+        } else {
+            regex::escape(query)
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(options.ignore_case)
+            .build()?;
+        Ok(Matcher {
+            regex,
+            whole_word: options.whole_word,
+            invert: options.invert,
+        })
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        let found = if self.whole_word {
+            self.matches(line).next().is_some()
+        } else {
+            self.regex.is_match(line)
+        };
+        found != self.invert
+    }
+
+    /// Byte ranges of the matches in `line`. Nothing matches in the lines an
+    /// inverted search selects.
+    pub fn spans(&self, line: &str) -> Vec<Range<usize>> {
+        if self.invert {
+            return vec![];
+        }
+        self.matches(line).collect()
+    }
+
+    /// The matches of the regex in `line`, left to right. With `whole_word`,
+    /// like grep, a match counts when it is bounded by the line's ends or by
+    /// characters that can't be part of a word; the boundaries are only
+    /// looked at, so adjacent words both match. After a match that isn't a
+    /// word the search goes on from its next character.
+    fn matches<'a>(&'a self, line: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+        let mut at = 0;
+        iter::from_fn(move || {
+            while at <= line.len() {
+                let found = self.regex.find_at(line, at)?.range();
+                let next =
+                    found.start + line[found.start..].chars().next().map_or(1, char::len_utf8);
+                if self.whole_word && !is_word(line, &found) {
+                    at = next;
+                    continue;
+                }
+                at = if found.is_empty() { next } else { found.end };
+                return Some(found);
+            }
+            None
+        })
+    }
+
+    /// `line` with the matches painted.
+    pub fn highlight(&self, line: &str) -> String {
+        let mut painted = String::new();
+        let mut end = 0;
+        for span in self.spans(line) {
+            painted.push_str(&line[end..span.start]);
+            painted.push_str(&paint(true, MATCH, &line[span.clone()]));
+            end = span.end;
+        }
+        painted.push_str(&line[end..]);
+        painted
+    }
+}
+
+/// Whether `span` of `line` has no word character right before or after it.
+fn is_word(line: &str, span: &Range<usize>) -> bool {
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    !word(line[..span.start].chars().next_back()) && !word(line[span.end..].chars().next())
+}
+
+/// How the selected lines are printed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Start lines with the path of their file, when searching a directory.
+    pub path: bool,
+    /// `-n`
+    pub line_numbers: bool,
+    /// `-B`, lines of context before each selected line. `-C` sets both.
+    pub before: usize,
+    /// `-A`, lines of context after each selected line.
+    pub after: usize,
+    /// `-c`: only the number of selected lines of each file.
+    pub count: bool,
+    /// `-l`: only the paths of the files with a selected line.
+    pub files_only: bool,
+    /// Highlight matches, set when stdout is a terminal.
+    pub color: bool,
+    /// `--json`: JSON Lines records instead of text, see `Record`.
+    pub json: bool,
+}
+
+impl Output {
+    fn context(&self) -> bool {
+        self.before > 0 || self.after > 0
     }
-    v
 }
 
 pub struct Config {
     query: String,
-    filename: String,
+    // A file, a directory searched recursively, or `-` for stdin.
+    path: String,
+    options: Options,
+    output: Output,
+    include: Vec<String>,
+    exclude: Vec<String>,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 3 {
-            return Err("length must 3 ");
+        let mut options = Options {
+            ignore_case: env::var("CASE_INSENSITIVE").is_ok(),
+            ..Options::default()
+        };
+        let mut output = Output::default();
+        let mut include = vec![];
+        let mut exclude = vec![];
+        let mut positional = vec![];
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            if arg == "--include" || arg == "--exclude" {
+                let glob = iter.next().ok_or("missing glob, see usage")?.clone();
+                if arg == "--include" {
+                    include.push(glob);
+                } else {
+                    exclude.push(glob);
+                }
+            } else if arg == "--json" {
+                output.json = true;
+            } else if arg == "--" {
+                positional.extend(iter.by_ref().cloned());
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                // Short flags can be combined, as in `-iw`, and the number of
+                // context lines can follow its flag, as in `-nC2`.
+                for (i, flag) in arg.char_indices().skip(1) {
+                    match flag {
+                        'i' => options.ignore_case = true,
+                        'w' => options.whole_word = true,
+                        'v' => options.invert = true,
+                        'F' => options.regex = false,
+                        'E' => options.regex = true,
+                        'n' => output.line_numbers = true,
+                        'c' => output.count = true,
+                        'l' => output.files_only = true,
+                        'A' | 'B' | 'C' => {
+                            let value = match &arg[i + 1..] {
+                                "" => iter.next().ok_or("missing context length, see usage")?,
+                                value => value,
+                            };
+                            let lines = value
+                                .parse()
+                                .map_err(|_| "invalid context length, see usage")?;
+                            match flag {
+                                'A' => output.after = lines,
+                                'B' => output.before = lines,
+                                _ => {
+                                    output.after = lines;
+                                    output.before = lines;
+                                }
+                            }
+                            break;
+                        }
+                        _ => return Err("unknown option, see usage"),
+                    }
+                }
+            } else {
+                positional.push(arg.clone());
+            }
         }
-        let query = args[1].clone();
-        let filename = args[2].clone();
-        Ok(Config { query, filename })
+        if output.json && (output.count || output.files_only) {
+            return Err("--json can't be combined with -c or -l");
+        }
+        if positional.len() == 1 {
+            positional.push("-".to_string());
+        }
+        if positional.len() != 2 {
+            return Err(USAGE);
+        }
+        let path = positional.pop().unwrap();
+        let query = positional.pop().unwrap();
+        Ok(Config {
+            query,
+            path,
+            options,
+            output,
+            include,
+            exclude,
+        })
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+
+    const CONTENTS: &str = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+    fn output_of(matcher: &Matcher, output: &Output, contents: &str) -> Vec<String> {
+        let mut lines = vec![];
+        search(matcher, output, "poem.txt", contents.as_bytes(), |line| {
+            lines.push(line);
+            Ok(())
+        })
+        .unwrap();
+        lines
+    }
+
+    fn matches(query: &str, options: Options, contents: &str) -> Vec<String> {
+        let matcher = Matcher::new(query, &options).unwrap();
+        output_of(&matcher, &Output::default(), contents)
+    }
+
     #[test]
     fn one_result() {
         let query = "duct";
@@ -50,6 +760,626 @@ Rust:
 safe, fast, productive.
 Pick three.";
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(
+            vec!["safe, fast, productive."],
+            matches(query, Options::default(), contents)
+        );
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        assert_eq!(vec!["Rust:"], matches("Rust", Options::default(), CONTENTS));
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            matches("rUsT", options, CONTENTS)
+        );
+    }
+
+    #[test]
+    fn whole_word() {
+        let options = Options {
+            whole_word: true,
+            ..Options::default()
+        };
+        assert_eq!(vec!["Rust:"], matches("Rust", options.clone(), CONTENTS));
+        assert_eq!(
+            vec!["Pick three."],
+            matches("three", options.clone(), CONTENTS)
+        );
+        assert!(matches("fas", options.clone(), CONTENTS).is_empty());
+        assert_eq!(vec!["a-b"], matches("a", options.clone(), "ab\na-b"));
+        // Punctuation at the ends of the query needs no word next to it.
+        assert_eq!(vec!["a -x"], matches("-x", options.clone(), "a -x\na-xy"));
+
+        // Adjacent words share their boundary, and a match inside a word
+        // doesn't hide a whole one after it.
+        let matcher = Matcher::new("rust", &options).unwrap();
+        assert_eq!(vec![0..4, 5..9], matcher.spans("rust rust"));
+        assert_eq!(vec![8..12], matcher.spans("trusty, rust"));
+        let regex = Options {
+            regex: true,
+            ..options
+        };
+        let matcher = Matcher::new("a+", &regex).unwrap();
+        assert_eq!(vec![4..6], matcher.spans("aab aa"));
+    }
+
+    #[test]
+    fn fixed_string() {
+        let contents = "a.c\nabc\n(a+)";
+        assert_eq!(vec!["a.c"], matches("a.c", Options::default(), contents));
+        assert_eq!(vec!["(a+)"], matches("(a+)", Options::default(), contents));
+    }
+
+    #[test]
+    fn regex() {
+        let options = Options {
+            regex: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            matches("ust[: ]", options.clone(), CONTENTS)
+        );
+        assert_eq!(
+            vec!["Pick three.", "Trust me."],
+            matches("^P|me.$", options.clone(), CONTENTS)
+        );
+        // Alternatives stay inside the word boundaries.
+        let words = Options {
+            whole_word: true,
+            ..options.clone()
+        };
+        assert_eq!(
+            vec!["Pick three."],
+            matches("Pic|thre", options.clone(), CONTENTS)
+        );
+        assert!(matches("Pic|thre", words, CONTENTS).is_empty());
+        assert!(Matcher::new("(", &options).is_err());
+    }
+
+    #[test]
+    fn invert_match() {
+        let options = Options {
+            invert: true,
+            ignore_case: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            vec!["safe, fast, productive.", "Pick three."],
+            matches("rust", options, CONTENTS)
+        );
+    }
+
+    #[test]
+    fn parse_flags() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        let config = Config::new(&args("minigrep -iv -E a+ poem.txt")).unwrap();
+        assert_eq!(("a+", "poem.txt"), (&config.query[..], &config.path[..]));
+        assert!(config.options.invert && config.options.regex && !config.options.whole_word);
+
+        let config = Config::new(&args("minigrep -E -F -w -- -v poem.txt")).unwrap();
+        assert_eq!("-v", config.query);
+        assert!(config.options.whole_word && !config.options.regex && !config.options.invert);
+
+        assert!(Config::new(&args("minigrep -x a poem.txt")).is_err());
+        assert_eq!("-", Config::new(&args("minigrep -i poem")).unwrap().path);
+        assert!(Config::new(&args("minigrep -i")).is_err());
+
+        let config = Config::new(&args(
+            "minigrep --include *.rs a src --exclude main.rs --include *.toml",
+        ))
+        .unwrap();
+        assert_eq!(vec!["*.rs", "*.toml"], config.include);
+        assert_eq!(vec!["main.rs"], config.exclude);
+        assert_eq!("src", config.path);
+        assert!(Config::new(&args("minigrep a src --include")).is_err());
+
+        let config = Config::new(&args("minigrep -nA 2 -lcB3 a src")).unwrap();
+        let output = Output {
+            line_numbers: true,
+            count: true,
+            files_only: true,
+            before: 3,
+            after: 2,
+            ..Output::default()
+        };
+        assert_eq!(output, config.output);
+        let config = Config::new(&args("minigrep -C1 a src")).unwrap();
+        assert_eq!((1, 1), (config.output.before, config.output.after));
+        assert!(Config::new(&args("minigrep -A x a src")).is_err());
+        assert!(Config::new(&args("minigrep a src -C")).is_err());
+        assert!(
+            Config::new(&args("minigrep --json a src"))
+                .unwrap()
+                .output
+                .json
+        );
+        assert!(Config::new(&args("minigrep --json -c a src")).is_err());
+    }
+
+    fn lines(query: &str, output: Output, contents: &str) -> Vec<String> {
+        let matcher = Matcher::new(query, &Options::default()).unwrap();
+        output_of(&matcher, &output, contents)
+    }
+
+    #[test]
+    fn line_numbers_and_context() {
+        let contents = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight";
+        let numbered = Output {
+            line_numbers: true,
+            ..Output::default()
+        };
+        assert_eq!(
+            vec!["2:two", "3:three", "8:eight"],
+            lines("t", numbered.clone(), contents)
+        );
+
+        // Overlapping context is printed once, a gap gets a separator.
+        let context = Output {
+            before: 1,
+            after: 1,
+            ..numbered.clone()
+        };
+        assert_eq!(
+            vec!["1-one", "2:two", "3:three", "4-four", "--", "7-seven", "8:eight"],
+            lines("t", context.clone(), contents)
+        );
+        assert_eq!(
+            vec!["poem.txt-4-four", "poem.txt:5:five", "poem.txt-6-six"],
+            lines(
+                "five",
+                Output {
+                    path: true,
+                    ..context
+                },
+                contents
+            )
+        );
+        assert_eq!(
+            vec!["six", "seven", "eight"],
+            lines(
+                "seven",
+                Output {
+                    before: 1,
+                    after: 5,
+                    ..Output::default()
+                },
+                contents
+            )
+        );
+    }
+
+    #[test]
+    fn lossy_lines() {
+        let matcher = Matcher::new("ok", &Options::default()).unwrap();
+        let output = Output {
+            line_numbers: true,
+            before: 1,
+            ..Output::default()
+        };
+        let input: &[u8] = b"caf\xe9\r\nok \xff\r\nnothing\nlast ok";
+        let mut lines = vec![];
+        let selected = search(&matcher, &output, STDIN, input, |line| {
+            lines.push(line);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(2, selected);
+        assert_eq!(
+            vec!["1-caf\u{fffd}", "2:ok \u{fffd}", "3-nothing", "4:last ok"],
+            lines
+        );
+    }
+
+    #[test]
+    fn long_reader_without_trailing_newline() {
+        let matcher = Matcher::new("match", &Options::default()).unwrap();
+        let output = Output {
+            line_numbers: true,
+            ..Output::default()
+        };
+        // Well past the capacity of a `BufReader`, the last line straddling
+        // its refills.
+        let mut input = "filler\n".repeat(2000);
+        let last = format!("{}match", "x".repeat(20_000));
+        input.push_str(&last);
+        let mut lines = vec![];
+        let reader = BufReader::new(input.as_bytes());
+        let selected = search(&matcher, &output, STDIN, reader, |line| {
+            lines.push(line);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(1, selected);
+        assert_eq!(vec![format!("2001:{}", last)], lines);
+    }
+
+    #[test]
+    fn count_and_files_only() {
+        let count = Output {
+            count: true,
+            ..Output::default()
+        };
+        assert_eq!(
+            vec!["2"],
+            lines(
+                "Rust",
+                count.clone(),
+                &CONTENTS.to_lowercase().replace("rust", "Rust")
+            )
+        );
+        assert_eq!(
+            vec!["poem.txt:0"],
+            lines(
+                "x",
+                Output {
+                    path: true,
+                    ..count.clone()
+                },
+                CONTENTS
+            )
+        );
+
+        let files_only = Output {
+            files_only: true,
+            ..count
+        };
+        assert_eq!(
+            vec!["poem.txt"],
+            lines("Pick", files_only.clone(), CONTENTS)
+        );
+        assert!(lines("x", files_only, CONTENTS).is_empty());
+    }
+
+    #[test]
+    fn highlight_matches() {
+        let options = Options {
+            ignore_case: true,
+            whole_word: true,
+            ..Options::default()
+        };
+        let matcher = Matcher::new("rust", &options).unwrap();
+        assert_eq!(
+            "\x1b[01;31mRust\x1b[m: trust \x1b[01;31mrust\x1b[m",
+            matcher.highlight("Rust: trust rust")
+        );
+        assert_eq!(
+            "\x1b[01;31mrust\x1b[m \x1b[01;31mrust\x1b[m",
+            matcher.highlight("rust rust")
+        );
+        let json = Output {
+            json: true,
+            ..Output::default()
+        };
+        let record: serde_json::Value =
+            serde_json::from_str(&output_of(&matcher, &json, "rust rust")[1]).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                {"text": "rust", "start": 0, "end": 4},
+                {"text": "rust", "start": 5, "end": 9},
+            ]),
+            record["submatches"]
+        );
+        let output = Output {
+            line_numbers: true,
+            color: true,
+            ..Output::default()
+        };
+        assert_eq!(
+            vec!["\x1b[32m1\x1b[m\x1b[36m:\x1b[m\x1b[01;31mRust\x1b[m:"],
+            output_of(&matcher, &output, CONTENTS)
+        );
+
+        let inverted = Matcher::new(
+            "rust",
+            &Options {
+                invert: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!("Pick three.", inverted.highlight("Pick three."));
+    }
+
+    #[test]
+    fn json_records() {
+        let options = Options {
+            regex: true,
+            ..Options::default()
+        };
+        let matcher = Matcher::new("fa[a-z]+|me", &options).unwrap();
+        let output = Output {
+            json: true,
+            after: 1,
+            ..Output::default()
+        };
+        let records = output_of(&matcher, &output, CONTENTS)
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let types = records
+            .iter()
+            .map(|record| record["type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["begin", "match", "context", "match", "end"], types);
+        assert_eq!(
+            serde_json::json!({
+                "type": "match",
+                "path": "poem.txt",
+                "line_number": 2,
+                "byte_offset": 6,
+                "text": "safe, fast, productive.",
+                "submatches": [{"text": "fast", "start": 6, "end": 10}],
+            }),
+            records[1]
+        );
+        assert_eq!("Pick three.", records[2]["text"]);
+        assert_eq!(42, records[3]["byte_offset"]);
+        assert_eq!(6, records[3]["submatches"][0]["start"]);
+        assert_eq!(2, records[4]["matched_lines"]);
+        assert!(records[4]["elapsed_secs"].is_f64());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let path = env::temp_dir().join(format!("minigrep-exit-{}.txt", std::process::id()));
+        fs::write(&path, CONTENTS).unwrap();
+        let code = |query: &str, path: &Path| {
+            let args = ["minigrep", "-c", query, &path.display().to_string()]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>();
+            run(Config::new(&args).unwrap()).unwrap()
+        };
+        assert_eq!(0, code("Pick", &path));
+        assert_eq!(1, code("nothing", &path));
+        assert_eq!(2, code("Pick", &path.with_extension("missing")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn walk_directory() {
+        let root = env::temp_dir().join(format!("minigrep-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (path, content) in &[
+            ("a.rs", "fn main() {}"),
+            ("b.txt", "main"),
+            ("src/lib.rs", "pub fn main() {}"),
+            ("src/gen/out.rs", "main"),
+            ("src/.hidden.rs", "main"),
+            (".gitignore", "gen/\n"),
+            ("data.bin", "main\0"),
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let found = |args: &str| {
+            let mut line = vec!["minigrep".to_string(), "main".to_string()];
+            line.extend(args.split(' ').map(String::from));
+            line.push(root.display().to_string());
+            let config = Config::new(&line[..]).unwrap();
+            files(&config)
+                .unwrap()
+                .0
+                .into_iter()
+                .filter(|path| !is_binary(&mut BufReader::new(File::open(path).unwrap())).unwrap())
+                .map(|path| path.strip_prefix(&root).unwrap().display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["a.rs", "b.txt", "src/lib.rs"], found("--"));
+        assert_eq!(
+            vec!["a.rs", "src/.hidden.rs", "src/lib.rs"],
+            found("--include *.rs")
+        );
+        assert_eq!(vec!["b.txt", "src/lib.rs"], found("--exclude a.rs"));
+        assert_eq!(
+            vec!["src/lib.rs"],
+            found("--include *.rs --exclude /a.rs --exclude .*")
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ordered_parallel_search() {
+        let root = env::temp_dir().join(format!("minigrep-pool-{}", std::process::id()));
+        let files = corpus(&root, 40, 50);
+        let matcher = Matcher::new("match", &Options::default()).unwrap();
+        let output = Output {
+            path: true,
+            ..Output::default()
+        };
+        let collect = |workers| {
+            let mut reports = vec![];
+            let mut lines = vec![];
+            search_files(
+                &matcher,
+                &output,
+                &files,
+                workers,
+                |path, event| match event {
+                    Event::Line(line) => lines.push(line),
+                    Event::Done(selected) => reports.push((
+                        path.to_path_buf(),
+                        selected.unwrap(),
+                        std::mem::take(&mut lines),
+                    )),
+                },
+            );
+            reports
+        };
+        let sequential = collect(1);
+        assert_eq!(files.len(), sequential.len());
+        assert!(sequential.iter().all(|(_, _, lines)| lines.len() == 5));
+        assert_eq!(sequential, collect(8));
+
+        let missing = vec![root.join("missing.txt")];
+        search_files(&matcher, &output, &missing, 4, |path, event| {
+            assert_eq!(missing[0], path);
+            assert!(matches!(event, Event::Done(Err(_))));
+        });
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_file_bounds_the_lookahead() {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::process::Command;
+        use std::time::Duration;
+
+        let root = env::temp_dir().join(format!("minigrep-slow-{}", std::process::id()));
+        let mut files = corpus(&root, 8, 5);
+        // Reading a FIFO waits for a writer, opening one to write without
+        // blocking fails while nothing reads it.
+        for index in [0, 4] {
+            files[index] = root.join(format!("slow-{}", index));
+            assert!(Command::new("mkfifo")
+                .arg(&files[index])
+                .status()
+                .unwrap()
+                .success());
+        }
+        let reading = |index: usize| {
+            fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&files[index])
+                .is_ok()
+        };
+        let write = |index: usize| fs::write(&files[index], "a match\n").unwrap();
+
+        let matcher = Matcher::new("match", &Options::default()).unwrap();
+        let output = Output::default();
+        let mut selected = vec![];
+        thread::scope(|scope| {
+            let search = scope.spawn(|| {
+                search_files(&matcher, &output, &files, 2, |_, event| {
+                    if let Event::Done(done) = event {
+                        selected.push(done.unwrap())
+                    }
+                })
+            });
+            // Files 1 to 3 are done, file 4 is out of reach while file 0
+            // hasn't been emitted.
+            thread::sleep(Duration::from_millis(200));
+            let early = reading(4);
+            write(0);
+            if !early {
+                write(4);
+            }
+            search.join().unwrap();
+            assert!(!early, "file 4 was opened before file 0 was emitted");
+        });
+        assert_eq!(vec![1; 8], selected);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streams_the_file_whose_turn_it_is() {
+        use std::process::Command;
+        use std::time::Duration;
+
+        let root = env::temp_dir().join(format!("minigrep-stream-{}", std::process::id()));
+        let mut files = corpus(&root, 4, 5);
+        files[0] = root.join("slow");
+        assert!(Command::new("mkfifo")
+            .arg(&files[0])
+            .status()
+            .unwrap()
+            .success());
+
+        let matcher = Matcher::new("match", &Options::default()).unwrap();
+        let output = Output::default();
+        let (events, received) = mpsc::channel();
+        thread::scope(|scope| {
+            let (matcher, output, files) = (&matcher, &output, &files);
+            scope.spawn(move || {
+                search_files(matcher, output, files, 2, |_, event| {
+                    events.send(event).unwrap()
+                })
+            });
+            // The line is printed while the file is still open.
+            let mut fifo = File::create(&files[0]).unwrap();
+            writeln!(fifo, "a match").unwrap();
+            match received.recv_timeout(Duration::from_secs(5)) {
+                Ok(Event::Line(line)) => assert_eq!("a match", line),
+                other => panic!("expected a line, got {:?}", other),
+            }
+            drop(fifo);
+        });
+        let done = received
+            .iter()
+            .filter(|event| matches!(event, Event::Done(Ok(_))))
+            .count();
+        assert_eq!(files.len(), done);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Write `count` files of `lines` lines, one in ten matching "match".
+    fn corpus(root: &Path, count: usize, lines: usize) -> Vec<PathBuf> {
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        (0..count)
+            .map(|n| {
+                let path = root.join(format!("{:05}.txt", n));
+                let text = (0..lines)
+                    .map(|line| match line % 10 {
+                        0 => format!("line {} of file {}: a match", line, n),
+                        _ => format!("line {} of file {}: nothing to see here", line, n),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                fs::write(&path, text).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    // cargo test --release --example minigrep -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_corpus() {
+        let root = env::temp_dir().join(format!("minigrep-bench-{}", std::process::id()));
+        let files = corpus(&root, 2000, 2000);
+        let matcher = Matcher::new(
+            r"file \d+7: a",
+            &Options {
+                regex: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut pools = vec![1, cpus];
+        pools.dedup();
+        for workers in pools {
+            let start = Instant::now();
+            let mut matches = 0;
+            let output = Output {
+                path: true,
+                ..Output::default()
+            };
+            search_files(&matcher, &output, &files, workers, |_, event| {
+                if let Event::Done(selected) = event {
+                    matches += selected.unwrap()
+                }
+            });
+            println!(
+                "{} files, {} workers: {} matches in {:?}",
+                files.len(),
+                workers,
+                matches,
+                start.elapsed()
+            );
+        }
+        fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -1,72 +1,422 @@
+//! The relay client, on tokio so boards can be driven from the same runtime
+//! as other services instead of a thread blocked on the port.
+
+use crate::async_serial::AsyncSerial;
+use crate::capture::Capture;
+use crate::error::RelayError;
+use crate::frame::{
+    self, Frame, FUNC_FLIP_ONE, FUNC_OFF_ONE, FUNC_ON_ONE, FUNC_READ_STATUS, FUNC_SET_MASK,
+};
+use crate::state::StateFile;
 use crate::Config;
-use serial::SystemPort;
-use std::error::Error;
 use std::ffi::OsString;
-use std::io::Write;
+use std::fmt;
+use std::iter;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// The byte stream to a board. Implemented by the real serial port and by
+/// the simulated board used in tests.
+pub trait Port: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Port for T {}
 
-pub struct Relay {
+pub struct Relay<P = Box<dyn Port>> {
     address: u8,
-    data: [u8; 8],
-    port: SystemPort,
+    port: P,
+    retry: Retry,
+    // How long to wait for a write or a reply.
+    timeout: Duration,
+    // Where to record the commanded state, if anywhere.
+    state: Option<StateFile>,
+}
+
+pub const CHANNELS: u8 = 32;
+
+/// On/off state of the 32 channels of a board, as reported by
+/// `FUNC_READ_STATUS`. Bit `n - 1` is channel `n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub mask: u32,
 }
 
-const DATA_HEADER: u8 = 0x55;
-const FUNC_READ_STATUS: u8 = 0x10;
-const FUNC_OFF_ONE: u8 = 0x11;
-const FUNC_ON_ONE: u8 = 0x12;
-const FUNC_FLIP_ONE: u8 = 0x20;
+impl Status {
+    pub fn is_on(&self, channel: u8) -> bool {
+        (1..=CHANNELS).contains(&channel) && self.mask & (1 << (channel - 1)) != 0
+    }
+
+    /// Channels in order with their state.
+    pub fn channels(&self) -> impl Iterator<Item = (u8, bool)> + '_ {
+        (1..=CHANNELS).map(move |channel| (channel, self.is_on(channel)))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (channel, on) in self.channels() {
+            writeln!(f, "{:>2}: {}", channel, if on { "on" } else { "off" })?;
+        }
+        Ok(())
+    }
+}
+
+/// How often to try an exchange with the board that timed out, doubling the
+/// pause between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl Retry {
+    /// The pauses before each attempt after the first.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        iter::successors(Some(self.backoff), |backoff| backoff.checked_mul(2))
+            .take(self.attempts.saturating_sub(1) as usize)
+    }
+}
+
+/// How long to wait before trying again after `result`, if at all: only
+/// timeouts are retried, while `delays` lasts.
+fn retry_after<T>(
+    result: &Result<T, RelayError>,
+    delays: &mut impl Iterator<Item = Duration>,
+) -> Option<Duration> {
+    match result {
+        Err(RelayError::Timeout) => delays.next(),
+        _ => None,
+    }
+}
+
+/// Check that `reply` is the status of the board at `address`.
+fn status(reply: Frame, address: u8) -> Result<Status, RelayError> {
+    if reply.header != frame::REPLY_HEADER || reply.function != FUNC_READ_STATUS {
+        return Err(RelayError::BadReply(format!("{:02x?}", reply)));
+    }
+    if reply.address != address {
+        return Err(RelayError::BadReply(format!(
+            "from board {} instead of {}",
+            reply.address, address
+        )));
+    }
+    Ok(Status { mask: reply.value })
+}
 
 impl Relay {
-    pub fn default(config: Config) -> Result<Relay, Box<dyn Error>> {
-        Relay::new(1, config)
+    /// Open the port of `config` on the current runtime.
+    pub fn open(address: u8, config: &Config) -> Result<Relay, RelayError> {
+        let mut port = serial::open(&OsString::from(&config.port))?;
+        config.settings.apply(&mut port)?;
+        let port = AsyncSerial::new(port)?;
+        let port: Box<dyn Port> = match &config.capture {
+            Some(path) => Box::new(Capture::create(port, path)?),
+            None => Box::new(port),
+        };
+        let mut relay = Relay::with_port(address, port);
+        relay.retry = config.retry;
+        relay.timeout = config.settings.timeout;
+        if let Some(path) = &config.state {
+            relay.state = Some(StateFile::open(path)?);
+        }
+        Ok(relay)
     }
-    pub fn new(address: u8, config: Config) -> Result<Relay, Box<dyn Error>> {
-        let port = serial::open(&OsString::from(config.port))?;
-        Ok(Relay {
-            port,
+}
+
+impl<P: Port> Relay<P> {
+    pub fn with_port(address: u8, port: P) -> Relay<P> {
+        Relay {
             address,
-            data: [DATA_HEADER, address, 0, 0, 0, 0, 0, 0],
-        })
+            port,
+            retry: Retry::default(),
+            timeout: Duration::from_millis(500),
+            state: None,
+        }
+    }
+
+    /// Address the following commands to another board on the same bus.
+    pub fn select(&mut self, address: u8) {
+        self.address = address;
+    }
+
+    /// Write `request` and read the reply if `reply` is set, until that
+    /// succeeds, fails with anything but a timeout, or runs out of attempts.
+    async fn exchange(&mut self, request: Frame, reply: bool) -> Result<Option<Frame>, RelayError> {
+        let request = request.encode();
+        let mut delays = self.retry.delays();
+        loop {
+            let result = self.try_exchange(&request, reply).await;
+            match retry_after(&result, &mut delays) {
+                Some(delay) => time::sleep(delay).await,
+                None => return result,
+            }
+        }
+    }
+
+    async fn try_exchange(
+        &mut self,
+        request: &[u8],
+        reply: bool,
+    ) -> Result<Option<Frame>, RelayError> {
+        let timeout = self.timeout;
+        if reply {
+            // Whatever is waiting can only be a stale reply, reading it
+            // instead of ours would put every later exchange one behind.
+            self.discard_input().await?;
+        }
+        time::timeout(timeout, self.port.write_all(request))
+            .await
+            .map_err(|_| RelayError::Timeout)??;
+        if !reply {
+            return Ok(None);
+        }
+        let mut bytes = [0u8; frame::LEN];
+        time::timeout(timeout, self.port.read_exact(&mut bytes))
+            .await
+            .map_err(|_| RelayError::Timeout)??;
+        Ok(Some(Frame::decode(bytes)?))
     }
-    fn sign(&mut self) {
-        let mut sum: u8 = 0;
-        for i in 0..7 {
-            sum += self.data[i]
+
+    /// Read and throw away whatever is already waiting.
+    async fn discard_input(&mut self) -> Result<usize, RelayError> {
+        let mut buf = [0u8; 64];
+        let mut discarded = 0;
+        while let Ok(read) = time::timeout(Duration::from_millis(1), self.port.read(&mut buf)).await
+        {
+            match read? {
+                0 => break,
+                n => discarded += n,
+            }
         }
-        self.data[7] = sum & 0xff;
+        Ok(discarded)
     }
 
-    fn status(&mut self) {
-        self.port.write(&self.data[..]).unwrap();
+    /// Send a request and save it to the state file, if there is one.
+    async fn send(&mut self, function: u8, value: u32) -> Result<(), RelayError> {
+        self.exchange(Frame::request(self.address, function, value), false)
+            .await?;
+        if let Some(state) = &mut self.state {
+            if state.record(self.address, function, value) {
+                state.save()?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn off(&mut self, index: u8) {
-        self.data[2] = FUNC_OFF_ONE;
-        self.data[3] = 0;
-        self.data[4] = 0;
-        self.data[5] = 0;
-        self.data[6] = index;
-        self.sign();
-        self.port.write(&self.data[..]).unwrap();
+    /// Ask the board for the state of every channel. The reply carries the
+    /// channel mask as its value, `d0` holding channels 1-8.
+    pub async fn read_status(&mut self) -> Result<Status, RelayError> {
+        let request = Frame::request(self.address, FUNC_READ_STATUS, 0);
+        let reply = self.exchange(request, true).await?;
+        status(reply.expect("a reply was read"), self.address)
     }
 
-    pub fn on(&mut self, index: u8) {
-        self.data[2] = FUNC_ON_ONE;
-        self.data[3] = 0;
-        self.data[4] = 0;
-        self.data[5] = 0;
-        self.data[6] = index;
-        self.sign();
-        self.port.write(&self.data[..]).unwrap();
+    pub async fn off(&mut self, index: u8) -> Result<(), RelayError> {
+        self.send(FUNC_OFF_ONE, index as u32).await
     }
 
-    pub fn flip(&mut self, index: u8) {
-        self.data[2] = FUNC_FLIP_ONE;
-        self.data[3] = 0;
-        self.data[4] = 0;
-        self.data[5] = 0;
-        self.data[6] = index;
-        self.sign();
-        self.port.write(&self.data[..]).unwrap();
+    pub async fn on(&mut self, index: u8) -> Result<(), RelayError> {
+        self.send(FUNC_ON_ONE, index as u32).await
+    }
+
+    pub async fn flip(&mut self, index: u8) -> Result<(), RelayError> {
+        self.send(FUNC_FLIP_ONE, index as u32).await
+    }
+
+    /// Switch every channel at once, bit `n - 1` of `mask` being channel `n`.
+    pub async fn set_mask(&mut self, mask: u32) -> Result<(), RelayError> {
+        self.send(FUNC_SET_MASK, mask).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Direction;
+    use crate::mock::{Fault, MockPort};
+
+    fn relay(port: &MockPort) -> Relay<MockPort> {
+        let mut relay = Relay::with_port(1, port.clone());
+        relay.timeout = Duration::from_millis(20);
+        relay.retry.backoff = Duration::from_millis(1);
+        relay
+    }
+
+    #[tokio::test]
+    async fn switch_and_status() {
+        let port = MockPort::new(1);
+        let mut relay = relay(&port);
+        relay.on(1).await.unwrap();
+        relay.on(3).await.unwrap();
+        relay.flip(3).await.unwrap();
+        relay.flip(5).await.unwrap();
+        relay.off(1).await.unwrap();
+        let status = relay.read_status().await.unwrap();
+        assert_eq!(Status { mask: 1 << 4 }, status);
+        assert_eq!(
+            vec![5],
+            status
+                .channels()
+                .filter(|c| c.1)
+                .map(|c| c.0)
+                .collect::<Vec<_>>()
+        );
+        assert!(!status.is_on(0) && !status.is_on(33));
+        assert_eq!(6, port.board().received.len());
+
+        relay.set_mask(0x8000_00f0).await.unwrap();
+        assert_eq!(0x8000_00f0, relay.read_status().await.unwrap().mask);
+
+        // Frames for another board on the bus are ignored by this one.
+        relay.select(2);
+        relay.set_mask(0).await.unwrap();
+        assert_eq!(0x8000_00f0, port.board().mask);
+    }
+
+    #[tokio::test]
+    async fn errors_and_retries() {
+        let port = MockPort::new(1);
+        let mut relay = relay(&port);
+
+        port.board().inject(Fault::BadChecksum);
+        assert!(matches!(
+            relay.read_status().await,
+            Err(RelayError::BadChecksum { .. })
+        ));
+        port.board().inject(Fault::Garbled);
+        assert!(matches!(
+            relay.read_status().await,
+            Err(RelayError::BadReply(_))
+        ));
+
+        // Two lost replies and a stalled write are retried.
+        port.board().inject(Fault::Timeout);
+        port.board().inject(Fault::Timeout);
+        assert_eq!(0, relay.read_status().await.unwrap().mask);
+        port.board().stalled_writes = 2;
+        relay.on(4).await.unwrap();
+        assert_eq!(1 << 3, port.board().mask);
+
+        for _ in 0..3 {
+            port.board().inject(Fault::Timeout);
+        }
+        assert!(matches!(
+            relay.read_status().await,
+            Err(RelayError::Timeout)
+        ));
+        port.board().stalled_writes = 3;
+        assert!(matches!(relay.off(4).await, Err(RelayError::Timeout)));
+        assert_eq!(1 << 3, port.board().mask);
+    }
+
+    #[tokio::test]
+    async fn other_address() {
+        let port = MockPort::new(2);
+        let mut relay = relay(&port);
+        relay.retry.attempts = 1;
+        relay.on(1).await.unwrap();
+        assert!(matches!(
+            relay.read_status().await,
+            Err(RelayError::Timeout)
+        ));
+        assert_eq!(0, port.board().mask);
+        assert!(port.board().received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_replies() {
+        let port = MockPort::new(1);
+        let mut relay = relay(&port);
+        relay.on(2).await.unwrap();
+        // A whole reply and half of one that came in after their exchanges
+        // gave up.
+        let stale = Frame::reply(1, FUNC_READ_STATUS, 0xff).encode();
+        port.board().send(&stale);
+        port.board().send(&stale[..3]);
+        assert_eq!(1 << 1, relay.read_status().await.unwrap().mask);
+        assert_eq!(1 << 1, relay.read_status().await.unwrap().mask);
+    }
+
+    #[test]
+    fn retry_delays() {
+        let retry = Retry {
+            attempts: 4,
+            backoff: Duration::from_millis(10),
+        };
+        assert_eq!(
+            vec![10, 20, 40],
+            retry
+                .delays()
+                .map(|delay| delay.as_millis())
+                .collect::<Vec<_>>()
+        );
+        let mut delays = retry.delays();
+        assert_eq!(None, retry_after(&Ok(()), &mut delays));
+        assert_eq!(
+            None,
+            retry_after::<()>(&Err(RelayError::BadReply(String::new())), &mut delays)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(10)),
+            retry_after::<()>(&Err(RelayError::Timeout), &mut delays)
+        );
+        let once = Retry {
+            attempts: 1,
+            ..retry
+        };
+        assert_eq!(0, once.delays().count());
+        assert_eq!(
+            0,
+            Retry {
+                attempts: 0,
+                ..retry
+            }
+            .delays()
+            .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn capture_traffic() {
+        let path = std::env::temp_dir().join(format!("relay-capture-{}", std::process::id()));
+        let port = MockPort::new(1);
+        let mut relay = Relay::with_port(1, Capture::create(port, &path).unwrap());
+        relay.on(3).await.unwrap();
+        relay.read_status().await.unwrap();
+        drop(relay);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let records = text
+            .lines()
+            .map(|line| crate::capture::parse_line(line).unwrap());
+        assert_eq!(
+            vec![
+                (
+                    Direction::Sent,
+                    Frame::request(1, FUNC_ON_ONE, 3).encode().to_vec()
+                ),
+                (
+                    Direction::Sent,
+                    Frame::request(1, FUNC_READ_STATUS, 0).encode().to_vec()
+                ),
+                (
+                    Direction::Received,
+                    Frame::reply(1, FUNC_READ_STATUS, 1 << 2).encode().to_vec()
+                ),
+            ],
+            records
+                .map(|r| (r.direction, r.bytes.clone()))
+                .collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub const USAGE: &str = "Usage: relay [--address <n>[,<n>...]] [--retries <n>] [--backoff <ms>] \
+                     <port> status
+       relay [options] <port> <on|off|flip|set> <channels>
+       relay [options] <port> pulse <channels> <duration>
+       relay [options] <port> schedule <file>
+       relay [options] <port> serve <host:port>
+       relay --state <file> [--dry-run] <port> restore
+Serial options: --config <file> --baud <n> --char-size <5-8> --parity <none|odd|even>
+                --stop-bits <1|2> --flow <none|software|hardware> --timeout <duration>
+With --state <file> every change is recorded, and restore sends it to the boards again.
+A schedule then also saves when it last ran, and catches up from there.
+With --capture <file> the serial traffic is logged, see the inspect example.
+Channels are numbers, ranges like 1-4,7 or all. `set` switches the given
+channels on and every other one off. Durations are like 500ms, 5s or 2m.";
+
+#[derive(Debug)]
+pub enum RelayError {
+    /// Bad command line.
+    Usage(String),
+    /// A value on the command line that doesn't parse.
+    Parse(String),
+    Io(io::Error),
+    /// The board didn't answer in time, even after retrying.
+    Timeout,
+    BadChecksum {
+        expected: u8,
+        actual: u8,
+    },
+    /// A reply that isn't a status frame from the addressed board.
+    BadReply(String),
+}
+
+impl RelayError {
+    /// Process exit code, distinct per kind of failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RelayError::Usage(_) => 2,
+            RelayError::Parse(_) => 3,
+            RelayError::Io(_) => 4,
+            RelayError::Timeout => 5,
+            RelayError::BadChecksum { .. } => 6,
+            RelayError::BadReply(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Usage(msg) => write!(f, "{}\n{}", msg, USAGE),
+            RelayError::Parse(msg) => write!(f, "{}", msg),
+            RelayError::Io(e) => write!(f, "serial port error: {}", e),
+            RelayError::Timeout => write!(f, "timed out waiting for the relay board"),
+            RelayError::BadChecksum { expected, actual } => write!(
+                f,
+                "bad checksum {:#04x} in reply, expected {:#04x}",
+                actual, expected
+            ),
+            RelayError::BadReply(msg) => write!(f, "bad reply: {}", msg),
+        }
+    }
+}
+
+impl Error for RelayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RelayError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RelayError {
+    fn from(e: io::Error) -> RelayError {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RelayError::Timeout,
+            _ => RelayError::Io(e),
+        }
+    }
+}
+
+impl From<serial::Error> for RelayError {
+    fn from(e: serial::Error) -> RelayError {
+        RelayError::from(io::Error::from(e))
+    }
+}
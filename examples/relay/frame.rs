@@ -0,0 +1,179 @@
+//! Codec for the 8-byte frames the relay boards speak:
+//!
+//! ```text
+//! header address function d3 d2 d1 d0 checksum
+//! ```
+//!
+//! Requests start with `0x55`, replies with `0x22`. The four data bytes are a
+//! big-endian value, a channel number or a channel mask, and the checksum is
+//! the low byte of the sum of the seven bytes before it.
+
+use crate::error::RelayError;
+
+pub const DATA_HEADER: u8 = 0x55;
+pub const REPLY_HEADER: u8 = 0x22;
+pub const FUNC_READ_STATUS: u8 = 0x10;
+pub const FUNC_OFF_ONE: u8 = 0x11;
+pub const FUNC_ON_ONE: u8 = 0x12;
+pub const FUNC_FLIP_ONE: u8 = 0x20;
+pub const FUNC_SET_MASK: u8 = 0x33;
+
+pub const LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub header: u8,
+    pub address: u8,
+    pub function: u8,
+    pub value: u32,
+}
+
+/// Sum of `bytes` modulo 256.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+impl Frame {
+    pub fn request(address: u8, function: u8, value: u32) -> Frame {
+        Frame {
+            header: DATA_HEADER,
+            address,
+            function,
+            value,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn reply(address: u8, function: u8, value: u32) -> Frame {
+        Frame {
+            header: REPLY_HEADER,
+            ..Frame::request(address, function, value)
+        }
+    }
+
+    pub fn encode(&self) -> [u8; LEN] {
+        let value = self.value.to_be_bytes();
+        let mut bytes = [
+            self.header,
+            self.address,
+            self.function,
+            value[0],
+            value[1],
+            value[2],
+            value[3],
+            0,
+        ];
+        bytes[7] = checksum(&bytes[..7]);
+        bytes
+    }
+
+    /// Parse a frame, verifying the header and checksum.
+    pub fn decode(bytes: [u8; LEN]) -> Result<Frame, RelayError> {
+        if bytes[0] != DATA_HEADER && bytes[0] != REPLY_HEADER {
+            return Err(RelayError::BadReply(format!(
+                "unexpected header {:#04x}",
+                bytes[0]
+            )));
+        }
+        let expected = checksum(&bytes[..7]);
+        if expected != bytes[7] {
+            return Err(RelayError::BadChecksum {
+                expected,
+                actual: bytes[7],
+            });
+        }
+        Ok(Frame {
+            header: bytes[0],
+            address: bytes[1],
+            function: bytes[2],
+            value: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
+        })
+    }
+}
+
+/// Drop the bytes at the front of `buffer` that can't start a frame,
+/// returning how many there were.
+pub fn resync(buffer: &mut Vec<u8>) -> usize {
+    let start = buffer
+        .iter()
+        .position(|&b| b == DATA_HEADER || b == REPLY_HEADER)
+        .unwrap_or(buffer.len());
+    buffer.drain(..start);
+    start
+}
+
+/// Take the next frame off the front of `buffer`, first skipping any bytes
+/// that can't start a frame. Returns `None` until a whole frame arrived.
+// Only the simulated board and the inspect example read frames.
+#[allow(dead_code)]
+pub fn next(buffer: &mut Vec<u8>) -> Option<Result<Frame, RelayError>> {
+    resync(buffer);
+    if buffer.len() < LEN {
+        return None;
+    }
+    let mut bytes = [0u8; LEN];
+    bytes.copy_from_slice(&buffer[..LEN]);
+    buffer.drain(..LEN);
+    Some(Frame::decode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn known_frames() {
+        // Every channel of board 1 on and off.
+        assert_eq!(
+            [0x55, 0x01, 0x33, 0xff, 0xff, 0xff, 0xff, 0x85],
+            Frame::request(1, FUNC_SET_MASK, u32::MAX).encode()
+        );
+        assert_eq!(
+            [0x55, 0x01, 0x33, 0x00, 0x00, 0x00, 0x00, 0x89],
+            Frame::request(1, FUNC_SET_MASK, 0).encode()
+        );
+    }
+
+    #[test]
+    fn round_trip_every_function() {
+        let mut rng = StdRng::seed_from_u64(0x55);
+        for function in 0..=u8::MAX {
+            for _ in 0..64 {
+                let frame = match rng.gen() {
+                    true => Frame::request(rng.gen(), function, rng.gen()),
+                    false => Frame::reply(rng.gen(), function, rng.gen()),
+                };
+                assert_eq!(frame, Frame::decode(frame.encode()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut rng = StdRng::seed_from_u64(0x22);
+        for _ in 0..10_000 {
+            let frame = Frame::request(rng.gen(), rng.gen(), rng.gen());
+            let mut bytes = frame.encode();
+            let i = rng.gen_range(0..LEN);
+            bytes[i] ^= rng.gen_range(1..=u8::MAX);
+            assert!(Frame::decode(bytes).is_err(), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn resync() {
+        let first = Frame::request(1, FUNC_ON_ONE, 3);
+        let second = Frame::request(2, FUNC_READ_STATUS, 0);
+        let mut buffer = vec![0x00, 0xff];
+        buffer.extend_from_slice(&first.encode());
+        buffer.extend_from_slice(&second.encode()[..5]);
+
+        assert_eq!(first, next(&mut buffer).unwrap().unwrap());
+        assert!(next(&mut buffer).is_none());
+        buffer.extend_from_slice(&second.encode()[5..]);
+        assert_eq!(second, next(&mut buffer).unwrap().unwrap());
+        assert!(buffer.is_empty());
+    }
+}
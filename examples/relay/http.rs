@@ -0,0 +1,528 @@
+//! HTTP control API for the `serve` mode of the relay tool.
+//!
+//! ```text
+//! GET  /relays                     status of every board
+//! PUT  /relays/{n}                 {"action": "on" | "off" | "flip"}
+//! POST /relays/{n}/pulse           {"duration": "500ms"}
+//! ```
+//!
+//! Channel commands go to the first board given with `--address` unless the
+//! request has a `?board=<address>` query. They reply with the new status of
+//! that board.
+//!
+//! A single owner task holds the serial port and runs one command at a time,
+//! so concurrent requests can't interleave frames on the wire. Handlers hand
+//! it their command over a channel and await the reply. actix runs on an
+//! older tokio than the port, so the owner has a runtime on a thread of its
+//! own. Pulses don't hold up the owner: it switches the channel on, replies,
+//! and switches it off again once the pulse is over, unless another command
+//! for that channel came in meanwhile.
+
+use crate::client::{Port, Relay, CHANNELS};
+use crate::error::RelayError;
+use crate::schedule;
+use crate::Config;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, put, web, App, HttpResponse, HttpServer};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    On,
+    Off,
+    Flip,
+}
+
+#[derive(Debug)]
+enum Command {
+    Status,
+    Switch {
+        board: Option<u8>,
+        channel: u8,
+        action: Action,
+    },
+    Pulse {
+        board: Option<u8>,
+        channel: u8,
+        length: Duration,
+    },
+}
+
+#[derive(Debug)]
+pub struct Request {
+    command: Command,
+    reply: oneshot::Sender<Result<Vec<BoardStatus>, RelayError>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardStatus {
+    pub address: u8,
+    /// Channels that are on.
+    pub on: Vec<u8>,
+    /// Why the board couldn't be read back after carrying out a command, in
+    /// which case `on` holds only the channel the command switched on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown: Option<String>,
+}
+
+struct Owner<P> {
+    relay: Relay<P>,
+    addresses: Vec<u8>,
+    // Pulses waiting to be switched off: when, board and channel.
+    pulses: Vec<(Instant, u8, u8)>,
+}
+
+impl<P: Port> Owner<P> {
+    async fn run(mut self, mut requests: UnboundedReceiver<Request>) {
+        loop {
+            self.end_pulses().await;
+            let next = self.pulses.iter().map(|pulse| pulse.0).min();
+            let request = match next {
+                Some(at) => match time::timeout_at(at.into(), requests.recv()).await {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                },
+                None => match requests.recv().await {
+                    Some(request) => request,
+                    None => break,
+                },
+            };
+            let result = self.execute(request.command).await;
+            let _ = request.reply.send(result);
+        }
+        // Don't leave channels on when shutting down mid pulse.
+        for pulse in &mut self.pulses {
+            pulse.0 = Instant::now();
+        }
+        self.end_pulses().await;
+    }
+
+    async fn end_pulses(&mut self) {
+        let now = Instant::now();
+        let (ended, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pulses)
+            .into_iter()
+            .partition(|pulse| pulse.0 <= now);
+        self.pulses = running;
+        for (_, address, channel) in ended {
+            self.relay.select(address);
+            if let Err(e) = self.relay.off(channel).await {
+                eprintln!("relay: ending pulse on {}/{}: {}", address, channel, e);
+            }
+        }
+    }
+
+    /// Forget the pulse running on a channel that was just switched again,
+    /// so its end doesn't cut the newer command short.
+    fn cancel_pulse(&mut self, address: u8, channel: u8) {
+        self.pulses
+            .retain(|&(_, a, c)| (a, c) != (address, channel));
+    }
+
+    fn board(&self, board: Option<u8>, channel: u8) -> Result<u8, RelayError> {
+        if !(1..=CHANNELS).contains(&channel) {
+            return Err(RelayError::Parse(format!(
+                "invalid channel {}, expected 1-{}",
+                channel, CHANNELS
+            )));
+        }
+        match board {
+            Some(address) if !self.addresses.contains(&address) => {
+                Err(RelayError::Parse(format!("unknown board {}", address)))
+            }
+            Some(address) => Ok(address),
+            None => Ok(self.addresses[0]),
+        }
+    }
+
+    async fn status(&mut self, address: u8) -> Result<BoardStatus, RelayError> {
+        self.relay.select(address);
+        let status = self.relay.read_status().await?;
+        Ok(BoardStatus {
+            address,
+            on: status.channels().filter(|c| c.1).map(|c| c.0).collect(),
+            unknown: None,
+        })
+    }
+
+    async fn execute(&mut self, command: Command) -> Result<Vec<BoardStatus>, RelayError> {
+        // The board, and the channels the command is known to leave on.
+        let (address, on) = match command {
+            Command::Status => {
+                let mut boards = vec![];
+                for address in self.addresses.clone() {
+                    boards.push(self.status(address).await?);
+                }
+                return Ok(boards);
+            }
+            Command::Switch {
+                board,
+                channel,
+                action,
+            } => {
+                let address = self.board(board, channel)?;
+                self.relay.select(address);
+                let on = match action {
+                    Action::On => {
+                        self.relay.on(channel).await?;
+                        vec![channel]
+                    }
+                    Action::Off => {
+                        self.relay.off(channel).await?;
+                        vec![]
+                    }
+                    Action::Flip => {
+                        self.relay.flip(channel).await?;
+                        vec![]
+                    }
+                };
+                self.cancel_pulse(address, channel);
+                (address, on)
+            }
+            Command::Pulse {
+                board,
+                channel,
+                length,
+            } => {
+                let address = self.board(board, channel)?;
+                let end = Instant::now().checked_add(length).ok_or_else(|| {
+                    RelayError::Parse(format!("pulse of {:?} is too long", length))
+                })?;
+                self.relay.select(address);
+                self.relay.on(channel).await?;
+                self.cancel_pulse(address, channel);
+                self.pulses.push((end, address, channel));
+                (address, vec![channel])
+            }
+        };
+        // The command was carried out, failing now would have a client that
+        // retries switch the channel twice.
+        match self.status(address).await {
+            Ok(status) => Ok(vec![status]),
+            Err(e) => Ok(vec![BoardStatus {
+                address,
+                on,
+                unknown: Some(e.to_string()),
+            }]),
+        }
+    }
+}
+
+/// Start the owner on a thread and runtime of its own, with the client
+/// `open` returns there. Returns the sender handlers submit commands to.
+pub fn spawn<P, F>(open: F, addresses: Vec<u8>) -> Result<UnboundedSender<Request>, RelayError>
+where
+    P: Port + 'static,
+    F: FnOnce() -> Result<Relay<P>, RelayError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (opened, wait_opened) = std_mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build();
+        let runtime = match runtime {
+            Ok(runtime) => runtime,
+            Err(e) => return drop(opened.send(Err(e.into()))),
+        };
+        runtime.block_on(async move {
+            let relay = match open() {
+                Ok(relay) => relay,
+                Err(e) => return drop(opened.send(Err(e))),
+            };
+            let _ = opened.send(Ok(()));
+            let owner = Owner {
+                relay,
+                addresses,
+                pulses: vec![],
+            };
+            owner.run(rx).await
+        })
+    });
+    wait_opened.recv().map_err(|_| stopped())??;
+    Ok(tx)
+}
+
+fn stopped() -> RelayError {
+    RelayError::Io(io::Error::other("relay owner stopped"))
+}
+
+fn error_response(e: RelayError) -> HttpResponse {
+    let status = match e {
+        RelayError::Usage(_) | RelayError::Parse(_) => StatusCode::BAD_REQUEST,
+        RelayError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        RelayError::BadChecksum { .. } | RelayError::BadReply(_) => StatusCode::BAD_GATEWAY,
+        RelayError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    HttpResponse::build(status).body(format!("{}\n", e))
+}
+
+async fn call(requests: web::Data<UnboundedSender<Request>>, command: Command) -> HttpResponse {
+    let (reply, replied) = oneshot::channel();
+    let result = match requests.send(Request { command, reply }) {
+        Ok(()) => replied.await.unwrap_or_else(|_| Err(stopped())),
+        Err(_) => Err(stopped()),
+    };
+    match result {
+        Ok(boards) => HttpResponse::Ok().json(boards),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BoardQuery {
+    board: Option<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchBody {
+    action: Action,
+}
+
+#[derive(Deserialize)]
+pub struct PulseBody {
+    duration: String,
+}
+
+#[get("/relays")]
+async fn get_relays(requests: web::Data<UnboundedSender<Request>>) -> HttpResponse {
+    call(requests, Command::Status).await
+}
+
+#[put("/relays/{n}")]
+async fn put_relay(
+    requests: web::Data<UnboundedSender<Request>>,
+    channel: web::Path<u8>,
+    query: web::Query<BoardQuery>,
+    body: web::Json<SwitchBody>,
+) -> HttpResponse {
+    let command = Command::Switch {
+        board: query.board,
+        channel: *channel,
+        action: body.action,
+    };
+    call(requests, command).await
+}
+
+#[post("/relays/{n}/pulse")]
+async fn post_pulse(
+    requests: web::Data<UnboundedSender<Request>>,
+    channel: web::Path<u8>,
+    query: web::Query<BoardQuery>,
+    body: web::Json<PulseBody>,
+) -> HttpResponse {
+    let length = match schedule::duration(&body.duration) {
+        Ok(length) => length,
+        Err(e) => return error_response(e),
+    };
+    let command = Command::Pulse {
+        board: query.board,
+        channel: *channel,
+        length,
+    };
+    call(requests, command).await
+}
+
+/// Serve the API on `bind` until the process is killed.
+pub fn serve(config: &Config, bind: &str) -> Result<(), RelayError> {
+    let addresses = config.addresses.clone();
+    let config = config.clone();
+    let requests = spawn(move || Relay::open(config.addresses[0], &config), addresses)?;
+    let mut system = actix_rt::System::new("relay");
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(requests.clone())
+            .service(get_relays)
+            .service(put_relay)
+            .service(post_pulse)
+    })
+    .bind(bind)?
+    .run();
+    println!("serving the relay API on http://{}", bind);
+    Ok(system.block_on(server)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Fault, MockPort};
+    use actix_web::test;
+
+    fn start(port: &MockPort) -> UnboundedSender<Request> {
+        let port = port.clone();
+        spawn(move || Ok(Relay::with_port(1, port)), vec![1]).unwrap()
+    }
+
+    fn call(requests: &UnboundedSender<Request>, command: Command) -> Vec<BoardStatus> {
+        let (reply, replied) = oneshot::channel();
+        requests.send(Request { command, reply }).unwrap();
+        replied.blocking_recv().unwrap().unwrap()
+    }
+
+    fn boards(body: &[u8]) -> Vec<BoardStatus> {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn api() {
+        let port = MockPort::new(1);
+        let requests = start(&port);
+        let mut app = test::init_service(
+            App::new()
+                .data(requests)
+                .service(get_relays)
+                .service(put_relay)
+                .service(post_pulse),
+        )
+        .await;
+
+        let request = test::TestRequest::put()
+            .uri("/relays/3")
+            .set_json(&serde_json::json!({"action": "on"}))
+            .to_request();
+        let body = test::read_response(&mut app, request).await;
+        assert_eq!(
+            vec![BoardStatus {
+                address: 1,
+                on: vec![3],
+                unknown: None,
+            }],
+            boards(&body)
+        );
+
+        let request = test::TestRequest::put()
+            .uri("/relays/3")
+            .set_json(&serde_json::json!({"action": "flip"}))
+            .to_request();
+        assert_eq!(
+            StatusCode::OK,
+            test::call_service(&mut app, request).await.status()
+        );
+        assert_eq!(0, port.board().mask);
+
+        let request = test::TestRequest::post()
+            .uri("/relays/5/pulse")
+            .set_json(&serde_json::json!({"duration": "50ms"}))
+            .to_request();
+        let body = test::read_response(&mut app, request).await;
+        assert_eq!(vec![5], boards(&body)[0].on);
+        thread::sleep(Duration::from_millis(100));
+        let request = test::TestRequest::get().uri("/relays").to_request();
+        let body = test::read_response(&mut app, request).await;
+        assert_eq!(
+            vec![BoardStatus {
+                address: 1,
+                on: vec![],
+                unknown: None,
+            }],
+            boards(&body)
+        );
+
+        for (uri, action, expected) in &[
+            ("/relays/33", "on", StatusCode::BAD_REQUEST),
+            ("/relays/1?board=7", "on", StatusCode::BAD_REQUEST),
+            ("/relays/1", "toggle", StatusCode::BAD_REQUEST),
+        ] {
+            let request = test::TestRequest::put()
+                .uri(uri)
+                .set_json(&serde_json::json!({ "action": action }))
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(*expected, response.status(), "{}", uri);
+        }
+
+        // Too long to tell when it ends, the channel isn't switched on.
+        let request = test::TestRequest::post()
+            .uri("/relays/6/pulse")
+            .set_json(&serde_json::json!({ "duration": format!("{}s", u64::MAX) }))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(0, port.board().mask);
+    }
+
+    #[test]
+    fn newer_commands_outlast_pulses() {
+        let port = MockPort::new(1);
+        let requests = start(&port);
+        let call = |command| call(&requests, command);
+        let pulse = |channel, ms| Command::Pulse {
+            board: None,
+            channel,
+            length: Duration::from_millis(ms),
+        };
+        call(pulse(2, 50));
+        call(pulse(2, 300));
+        call(pulse(3, 50));
+        call(Command::Switch {
+            board: None,
+            channel: 3,
+            action: Action::On,
+        });
+        call(pulse(4, 50));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(0b110, port.board().mask);
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(0b100, port.board().mask);
+    }
+
+    #[test]
+    fn switched_but_unread() {
+        let port = MockPort::new(1);
+        let requests = start(&port);
+        let switch = |action| Command::Switch {
+            board: None,
+            channel: 2,
+            action,
+        };
+        port.board().inject(Fault::BadChecksum);
+        let boards = call(&requests, switch(Action::On));
+        assert_eq!(vec![2], boards[0].on);
+        assert!(boards[0].unknown.is_some());
+        assert_eq!(0b10, port.board().mask);
+
+        port.board().inject(Fault::BadChecksum);
+        let boards = call(&requests, switch(Action::Flip));
+        assert!(boards[0].on.is_empty());
+        assert!(boards[0].unknown.is_some());
+        assert_eq!(0, port.board().mask);
+
+        let boards = call(&requests, switch(Action::On));
+        assert_eq!(None, boards[0].unknown);
+        let json = serde_json::to_value(&boards).unwrap();
+        assert_eq!(serde_json::json!([{"address": 1, "on": [2]}]), json);
+    }
+
+    #[test]
+    fn concurrent_requests_are_serialised() {
+        let port = MockPort::new(1);
+        let requests = start(&port);
+        let clients = (1..=8u8)
+            .map(|channel| {
+                let requests = requests.clone();
+                thread::spawn(move || {
+                    let command = Command::Switch {
+                        board: None,
+                        channel,
+                        action: Action::On,
+                    };
+                    call(&requests, command);
+                })
+            })
+            .collect::<Vec<_>>();
+        for client in clients {
+            client.join().unwrap();
+        }
+        // Every frame arrived whole: 8 switches and 8 status reads.
+        assert_eq!(0xff, port.board().mask);
+        assert_eq!(16, port.board().received.len());
+    }
+}
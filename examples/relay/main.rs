@@ -1,73 +1,451 @@
+#[path = "../common/async_serial.rs"]
+#[allow(dead_code)]
+mod async_serial;
+#[path = "../common/capture.rs"]
+#[allow(dead_code)]
+mod capture;
 mod client;
+mod error;
+mod frame;
+mod http;
+#[cfg(test)]
+mod mock;
+mod schedule;
+mod settings;
+mod state;
 
-use std::ffi::OsString;
-use std::io::Write;
+use chrono::{Local, Timelike};
+use client::{Port, Relay, Retry};
+use error::RelayError;
+use frame::Frame;
+use schedule::{Action, Entry};
+use settings::Settings;
+use state::{LastRun, StateFile};
+use std::str::FromStr;
 use std::time::Duration;
-use std::{env, thread};
+use std::{env, fs, process};
+use tokio::time;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     port: String,
     handle: String,
     branch: Vec<String>,
+    retry: Retry,
+    // Boards on the bus the command goes to, in order.
+    addresses: Vec<u8>,
+    settings: Settings,
+    // File recording the commanded state of every board.
+    state: Option<String>,
+    // Print the frames `restore` would send instead of sending them.
+    dry_run: bool,
+    // File logging the traffic on the serial port.
+    capture: Option<String>,
 }
 
 impl Config {
-    fn new(args: Vec<String>) -> Config {
-        if args.len() < 3 {
-            panic!("length")
+    fn new(args: Vec<String>) -> Result<Config, RelayError> {
+        let mut retry = Retry::default();
+        let mut addresses = vec![1];
+        let mut config_file = None;
+        let mut state = None;
+        let mut dry_run = false;
+        let mut capture = None;
+        let mut overrides = Vec::new();
+        let mut positional = Vec::new();
+        let mut iter = args.into_iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--retries" => retry.attempts = parse(&arg, iter.next())?,
+                "--backoff" => retry.backoff = Duration::from_millis(parse(&arg, iter.next())?),
+                "--address" => {
+                    let value: String = parse(&arg, iter.next())?;
+                    addresses = value
+                        .split(',')
+                        .map(|address| parse(&arg, Some(address.to_string())))
+                        .collect::<Result<_, _>>()?;
+                }
+                "--config" => config_file = Some(parse::<String>(&arg, iter.next())?),
+                "--state" => state = Some(parse(&arg, iter.next())?),
+                "--dry-run" => dry_run = true,
+                "--capture" => capture = Some(parse(&arg, iter.next())?),
+                _ => match arg.strip_prefix("--") {
+                    Some(key) if settings::KEYS.contains(&key) => {
+                        let value: String = parse(&arg, iter.next())?;
+                        overrides.push((key.to_string(), value));
+                    }
+                    Some(_) => return Err(RelayError::Usage(format!("unknown option {}", arg))),
+                    None => positional.push(arg),
+                },
+            }
         }
-        let mut branch = Vec::new();
-        for arg in 3..args.len() {
-            branch.push(args[arg].clone())
+        if positional.len() < 2 {
+            return Err(RelayError::Usage("missing port or command".to_string()));
         }
-        Config {
-            port: args[1].clone(),
-            handle: args[2].clone(),
+        let mut settings = Settings::default();
+        if let Some(path) = config_file {
+            settings.load(&fs::read_to_string(path)?)?;
+        }
+        for (key, value) in overrides {
+            settings.set(&key, &value)?;
+        }
+        let branch = positional.split_off(2);
+        Ok(Config {
+            port: positional[0].clone(),
+            handle: positional[1].clone(),
             branch,
+            retry,
+            addresses,
+            settings,
+            state,
+            dry_run,
+            capture,
+        })
+    }
+}
+
+fn parse<T: FromStr>(option: &str, value: Option<String>) -> Result<T, RelayError> {
+    let value = value.ok_or_else(|| RelayError::Usage(format!("missing value for {}", option)))?;
+    value
+        .parse()
+        .map_err(|_| RelayError::Parse(format!("invalid value {:?} for {}", value, option)))
+}
+
+/// Parse channel arguments like `1-4,7` or `all` into a mask, bit `n - 1`
+/// being channel `n`.
+fn channels(branch: &[String]) -> Result<u32, RelayError> {
+    let invalid = |part: &str| {
+        RelayError::Parse(format!(
+            "invalid channel {:?}, expected 1-{}, a range like 1-4 or all",
+            part,
+            client::CHANNELS
+        ))
+    };
+    let channel = |part: &str| match part.parse::<u8>() {
+        Ok(channel) if (1..=client::CHANNELS).contains(&channel) => Ok(channel),
+        _ => Err(invalid(part)),
+    };
+    let mut mask = 0u32;
+    for part in branch.iter().flat_map(|arg| arg.split(',')) {
+        if part == "all" {
+            mask = u32::MAX;
+            continue;
+        }
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (channel(first)?, channel(last)?),
+            None => (channel(part)?, channel(part)?),
+        };
+        if first > last {
+            return Err(invalid(part));
+        }
+        for channel in first..=last {
+            mask |= 1 << (channel - 1);
+        }
+    }
+    Ok(mask)
+}
+
+/// Switch the channels in `mask` of every board on or off.
+async fn switch<P: Port>(
+    relay: &mut Relay<P>,
+    addresses: &[u8],
+    mask: u32,
+    on: bool,
+) -> Result<(), RelayError> {
+    for &address in addresses {
+        relay.select(address);
+        for channel in (1..=client::CHANNELS).filter(|c| mask & (1 << (c - 1)) != 0) {
+            match on {
+                true => relay.on(channel).await?,
+                false => relay.off(channel).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Switch the channels on, wait `length`, and switch them off again.
+async fn pulse<P: Port>(
+    relay: &mut Relay<P>,
+    addresses: &[u8],
+    mask: u32,
+    length: Duration,
+) -> Result<(), RelayError> {
+    switch(relay, addresses, mask, true).await?;
+    time::sleep(length).await;
+    switch(relay, addresses, mask, false).await
+}
+
+/// Run a schedule until killed, checking for due entries every minute.
+/// Errors talking to the board are reported and the schedule carries on.
+async fn run_schedule<P: Port>(
+    relay: &mut Relay<P>,
+    addresses: &[u8],
+    entries: &[Entry],
+    last_run: Option<LastRun>,
+) -> Result<(), RelayError> {
+    let saved = match &last_run {
+        Some(last_run) => last_run.load()?,
+        None => None,
+    };
+    let mut last = saved.unwrap_or_else(|| {
+        Local::now().naive_local() - chrono::Duration::from_std(schedule::LOOKBACK).unwrap()
+    });
+    loop {
+        let now = Local::now().naive_local();
+        let plan = schedule::plan(entries, last, now);
+        if plan.skipped > 0 {
+            println!("{}: skipped {} missed pulses", now, plan.skipped);
+        }
+        let result: Result<(), RelayError> = async {
+            for (&channel, &on) in &plan.catch_up {
+                println!(
+                    "{}: catching up, channel {} {}",
+                    now,
+                    channel,
+                    if on { "on" } else { "off" }
+                );
+                switch(relay, addresses, 1 << (channel - 1), on).await?;
+            }
+            for entry in &plan.run {
+                println!("{}: {:?} {:#x}", now, entry.action, entry.mask);
+                match entry.action {
+                    Action::On => switch(relay, addresses, entry.mask, true).await?,
+                    Action::Off => switch(relay, addresses, entry.mask, false).await?,
+                    Action::Pulse(length) => pulse(relay, addresses, entry.mask, length).await?,
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            eprintln!("relay: {}", e);
         }
+        last = now;
+        if let Some(last_run) = &last_run {
+            if let Err(e) = last_run.save(now) {
+                eprintln!("relay: saving the last run: {}", e);
+            }
+        }
+        time::sleep(Duration::from_secs(60 - now.second() as u64)).await;
     }
 }
 
-pub fn run(config: Config) {
-    let mut port = serial::open(&OsString::from(config.port)).expect("");
-    let data: Vec<u8>;
-    if config.handle.eq("on") {
-        println!("on...");
-        data = Vec::from([0x55, 0x01, 0x33, 0xff, 0xff, 0xff, 0xff, 0x85]);
-    } else {
-        println!("off...");
-        data = Vec::from([0x55, 0x01, 0x33, 0x00, 0x00, 0x00, 0x00, 0x89]);
+fn run(config: Config) -> Result<(), RelayError> {
+    // The HTTP server runs on actix's own runtime, and starts the one of the
+    // client itself.
+    if config.handle == "serve" {
+        let bind = match &config.branch[..] {
+            [bind] => bind,
+            _ => return Err(RelayError::Usage("serve takes an address".to_string())),
+        };
+        return http::serve(&config, bind);
     }
-    port.write(&data).unwrap();
-    thread::sleep(Duration::from_millis(100))
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(command(&config))
 }
 
-fn run2(config: Config) {
-    let mut relay = client::Relay::default(config.clone()).unwrap();
-    if config.handle.eq("on") {
-        println!("on...{:?}", config.branch);
-        for branch in config.branch {
-            relay.on(branch.parse::<u8>().unwrap());
+/// Run every other command on a tokio runtime.
+async fn command(config: &Config) -> Result<(), RelayError> {
+    let addresses = &config.addresses;
+    match config.handle.as_str() {
+        "schedule" => {
+            let path = match &config.branch[..] {
+                [path] => path,
+                _ => return Err(RelayError::Usage("schedule takes a file".to_string())),
+            };
+            let entries = schedule::parse(&fs::read_to_string(path)?)?;
+            let last_run = config.state.as_ref().map(LastRun::beside);
+            let mut relay = Relay::open(addresses[0], config)?;
+            return run_schedule(&mut relay, addresses, &entries, last_run).await;
+        }
+        "restore" => {
+            let path = config
+                .state
+                .as_ref()
+                .ok_or_else(|| RelayError::Usage("restore needs the --state file".to_string()))?;
+            let state = StateFile::open(path)?;
+            if config.dry_run {
+                for (address, mask) in &state.boards {
+                    let request = Frame::request(*address, frame::FUNC_SET_MASK, *mask);
+                    println!("board {}: {:02x?}", address, request.encode());
+                }
+                return Ok(());
+            }
+            let mut relay = Relay::open(addresses[0], config)?;
+            for (&address, &mask) in &state.boards {
+                relay.select(address);
+                relay.set_mask(mask).await?;
+            }
+            return Ok(());
         }
-    } else if config.handle.eq("off") {
-        println!("off... {:?}", config.branch);
-        for branch in config.branch {
-            relay.off(branch.parse::<u8>().unwrap());
+        "pulse" => {
+            let (length, branch) = match config.branch.split_last() {
+                Some((length, branch)) if !branch.is_empty() => (length, branch),
+                _ => {
+                    return Err(RelayError::Usage(
+                        "pulse takes channels and a duration".to_string(),
+                    ))
+                }
+            };
+            let length = schedule::duration(length)?;
+            let mask = channels(branch)?;
+            let mut relay = Relay::open(addresses[0], config)?;
+            return pulse(&mut relay, addresses, mask, length).await;
         }
-    } else {
-        println!("flip... {:?}", config.branch);
-        for branch in config.branch {
-            relay.flip(branch.parse::<u8>().unwrap());
+        _ => {}
+    }
+
+    if config.handle != "status" && config.branch.is_empty() {
+        return Err(RelayError::Usage(format!(
+            "{} takes channels like 1-4,7 or all",
+            config.handle
+        )));
+    }
+    let mask = channels(&config.branch)?;
+    let selected = (1..=client::CHANNELS)
+        .filter(|channel| mask & (1 << (channel - 1)) != 0)
+        .collect::<Vec<u8>>();
+    let mut relay = Relay::open(addresses[0], config)?;
+    for &address in addresses {
+        relay.select(address);
+        match config.handle.as_str() {
+            "status" => {
+                if addresses.len() > 1 {
+                    println!("board {}:", address);
+                }
+                print!("{}", relay.read_status().await?);
+            }
+            // Whole boards are switched with a single frame.
+            "on" if mask == u32::MAX => relay.set_mask(u32::MAX).await?,
+            "off" if mask == u32::MAX => relay.set_mask(0).await?,
+            "set" => relay.set_mask(mask).await?,
+            "on" | "off" | "flip" => {
+                println!("{}... board {} {:?}", config.handle, address, selected);
+                for &channel in &selected {
+                    match config.handle.as_str() {
+                        "on" => relay.on(channel).await?,
+                        "off" => relay.off(channel).await?,
+                        _ => relay.flip(channel).await?,
+                    }
+                }
+            }
+            handle => return Err(RelayError::Usage(format!("unknown command {}", handle))),
         }
     }
+    Ok(())
 }
 
 fn main() {
     let args = env::args().collect::<Vec<String>>();
-    println!("{:?}", args);
-    let config = Config::new(args);
-    println!("{:?}", config);
-    // run(config)
-    run2(config);
+    if let Err(e) = Config::new(args).and_then(run) {
+        eprintln!("relay: {}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &str) -> Result<Config, RelayError> {
+        Config::new(args.split_whitespace().map(String::from).collect())
+    }
+
+    #[test]
+    fn config_errors() {
+        let c = config("relay --retries 5 /dev/ttyUSB0 on 1 2 --backoff 20").unwrap();
+        assert_eq!(("/dev/ttyUSB0", "on"), (c.port.as_str(), c.handle.as_str()));
+        assert_eq!(vec!["1", "2"], c.branch);
+        assert_eq!(5, c.retry.attempts);
+        assert_eq!(Duration::from_millis(20), c.retry.backoff);
+
+        assert_eq!(2, config("relay /dev/ttyUSB0").unwrap_err().exit_code());
+        assert_eq!(2, config("relay --verbose a b").unwrap_err().exit_code());
+        assert_eq!(3, config("relay --retries x a b").unwrap_err().exit_code());
+        assert_eq!(
+            3,
+            config("relay --address 1,x a b").unwrap_err().exit_code()
+        );
+        assert_eq!(vec![1], c.addresses);
+        assert_eq!(Settings::default(), c.settings);
+        assert_eq!((None, false), (c.state, c.dry_run));
+        let c = config("relay --state relay.state a restore --dry-run").unwrap();
+        assert_eq!(
+            (Some("relay.state".to_string()), true),
+            (c.state, c.dry_run)
+        );
+        let c = config("relay --capture wire.log a status").unwrap();
+        assert_eq!(Some("wire.log".to_string()), c.capture);
+        let c = config("relay --parity odd --baud 4800 a b").unwrap();
+        assert_eq!(serial::BaudRate::Baud4800, c.settings.baud);
+        assert_eq!(serial::Parity::ParityOdd, c.settings.parity);
+        assert_eq!(3, config("relay --baud x a b").unwrap_err().exit_code());
+        // Setting names are only options with the dashes.
+        let c = config("relay a schedule timeout").unwrap();
+        assert_eq!(vec!["timeout"], c.branch);
+        assert_eq!(Settings::default(), c.settings);
+        assert_eq!(
+            4,
+            config("relay --config /nonexistent a b")
+                .unwrap_err()
+                .exit_code()
+        );
+        assert_eq!(
+            vec![2, 3],
+            config("relay --address 2,3 a b").unwrap().addresses
+        );
+    }
+
+    #[test]
+    fn missing_channels() {
+        // Caught before the port is opened.
+        for command in &["on", "off", "flip", "set", "pulse 1s"] {
+            let config = config(&format!("relay /nonexistent {}", command)).unwrap();
+            match run(config) {
+                Err(RelayError::Usage(_)) => {}
+                result => panic!("{}: {:?}", command, result.map_err(|e| e.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn channel_ranges() {
+        let branch = |args: &str| {
+            channels(
+                &args
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(0b100_1111, branch("1-4,7").unwrap());
+        assert_eq!(0b100_1111, branch("1-3 4 7 7").unwrap());
+        assert_eq!(1 << 31, branch("32").unwrap());
+        assert_eq!(u32::MAX, branch("all").unwrap());
+        assert_eq!(u32::MAX, branch("1-32").unwrap());
+        assert_eq!(0, branch("").unwrap());
+        for bad in &["0", "33", "one", "4-1", "1-", "-3", "1,,2"] {
+            assert_eq!(3, branch(bad).unwrap_err().exit_code(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn pulse_channels() {
+        let port = mock::MockPort::new(2);
+        let mut relay = Relay::with_port(1, port.clone());
+        pulse(&mut relay, &[1, 2], 0b101, Duration::from_millis(1))
+            .await
+            .unwrap();
+        let board = port.board();
+        let functions = board
+            .received
+            .iter()
+            .map(|frame| (frame.function, frame.value))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(0x12, 1), (0x12, 3), (0x11, 1), (0x11, 3)], functions);
+        assert_eq!(0, board.mask);
+    }
 }
@@ -0,0 +1,157 @@
+//! A simulated relay board speaking the 0x55 protocol, so the client can be
+//! tested without hardware. Like the real board it ignores frames with a bad
+//! checksum or another address, and only replies to status queries.
+
+use crate::frame::{
+    self, Frame, DATA_HEADER, FUNC_FLIP_ONE, FUNC_OFF_ONE, FUNC_ON_ONE, FUNC_READ_STATUS,
+    FUNC_SET_MASK,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A fault applied to the next reply of the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Reply with a wrong checksum.
+    BadChecksum,
+    /// Drop the request without replying.
+    Timeout,
+    /// Reply with bytes that aren't a frame.
+    Garbled,
+}
+
+#[derive(Debug, Default)]
+pub struct Board {
+    pub address: u8,
+    pub mask: u32,
+    /// Every valid frame addressed to this board, in order.
+    pub received: Vec<Frame>,
+    /// Number of upcoming writes that fail with a timeout.
+    pub stalled_writes: u32,
+    faults: VecDeque<Fault>,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+}
+
+impl Board {
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push_back(fault);
+    }
+
+    /// Put `bytes` on the line for the client to read, as if the board sent
+    /// them unasked.
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes);
+    }
+
+    fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+        while let Some(frame) = frame::next(&mut self.input) {
+            match frame {
+                Ok(frame) if frame.header == DATA_HEADER && frame.address == self.address => {
+                    self.received.push(frame);
+                    self.execute(frame);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn execute(&mut self, frame: Frame) {
+        let bit = match frame.value {
+            channel @ 1..=32 => 1u32 << (channel - 1),
+            _ => 0,
+        };
+        match frame.function {
+            FUNC_OFF_ONE => self.mask &= !bit,
+            FUNC_ON_ONE => self.mask |= bit,
+            FUNC_FLIP_ONE => self.mask ^= bit,
+            FUNC_SET_MASK => self.mask = frame.value,
+            FUNC_READ_STATUS => self.reply(),
+            _ => {}
+        }
+    }
+
+    fn reply(&mut self) {
+        let mut reply = Frame::reply(self.address, FUNC_READ_STATUS, self.mask).encode();
+        match self.faults.pop_front() {
+            Some(Fault::BadChecksum) => reply[7] = reply[7].wrapping_add(1),
+            Some(Fault::Timeout) => return,
+            Some(Fault::Garbled) => reply = [0xde, 0xad, 0xbe, 0xef, 0x00, 0xff, 0x13, 0x37],
+            None => {}
+        }
+        self.output.extend(reply);
+    }
+}
+
+/// The serial side of a simulated board. Clones share the same board, so a
+/// test can keep one to inspect the board while a `Relay` owns another.
+#[derive(Debug, Clone)]
+pub struct MockPort {
+    board: Arc<Mutex<Board>>,
+}
+
+impl MockPort {
+    pub fn new(address: u8) -> MockPort {
+        MockPort {
+            board: Arc::new(Mutex::new(Board {
+                address,
+                ..Board::default()
+            })),
+        }
+    }
+
+    pub fn board(&self) -> MutexGuard<'_, Board> {
+        self.board.lock().unwrap()
+    }
+}
+
+// A silent board never becomes readable, the client's timeout gives up on
+// it.
+impl AsyncRead for MockPort {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut board = self.board();
+        if board.output.is_empty() {
+            return Poll::Pending;
+        }
+        let n = buf.remaining().min(board.output.len());
+        let bytes: Vec<u8> = board.output.drain(..n).collect();
+        buf.put_slice(&bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MockPort {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut board = self.board();
+        if board.stalled_writes > 0 {
+            board.stalled_writes -= 1;
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            )));
+        }
+        board.receive(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,255 @@
+//! Cron-like schedules for the `schedule` mode of the relay tool.
+//!
+//! A schedule file has one entry per line, `#` starting a comment:
+//!
+//! ```text
+//! # minute hour weekday channels action
+//! 0    8  mon-fri  3    on
+//! 30   17 mon-fri  3    off
+//! 0,30 *  *        1-4  pulse 5s
+//! ```
+//!
+//! Minutes (0-59), hours (0-23) and weekdays (`sun`-`sat` or 0-6, 0 being
+//! Sunday) take `*`, numbers, ranges and comma separated lists. Channels are
+//! given like on the command line. Times are local time.
+//!
+//! Catch-up policy: an entry is on time if it runs within `GRACE` of its
+//! scheduled minute. When the tool starts, or wakes up late, it looks back up
+//! to `LOOKBACK` for missed entries, or only to its last run if that was
+//! saved next to the `--state` file. Missed `on` and `off` entries are
+//! collapsed into the state they would have left each channel in, and that
+//! state is applied once before anything else, so a restart leaves the
+//! channels as if the tool had never stopped. Missed pulses are dropped,
+//! since power-cycling a device late is rarely what anyone wants.
+
+use crate::error::RelayError;
+use chrono::{Datelike, Duration as Span, NaiveDateTime, Timelike};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const GRACE: Duration = Duration::from_secs(60);
+pub const LOOKBACK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    On,
+    Off,
+    Pulse(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    // Bit n set when the entry runs at minute, hour or weekday n.
+    minutes: u64,
+    hours: u32,
+    weekdays: u8,
+    pub mask: u32,
+    pub action: Action,
+}
+
+impl Entry {
+    pub fn matches(&self, at: NaiveDateTime) -> bool {
+        self.minutes & (1 << at.minute()) != 0
+            && self.hours & (1 << at.hour()) != 0
+            && self.weekdays & (1 << at.weekday().num_days_from_sunday()) != 0
+    }
+}
+
+/// Parse `500ms`, `5s`, `2m` or a bare number of seconds.
+pub fn duration(value: &str) -> Result<Duration, RelayError> {
+    let invalid = || RelayError::Parse(format!("invalid duration {:?}", value));
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// Bits for a field like `*`, `5`, `1-5` or `0,30`.
+fn field(value: &str, max: u32, names: &[&str]) -> Option<u64> {
+    let number = |s: &str| match names.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+        Some(i) => Some(i as u32),
+        None => s.parse::<u32>().ok().filter(|&n| n <= max),
+    };
+    let mut bits = 0u64;
+    for part in value.split(',') {
+        let (first, last) = match part.split_once('-') {
+            _ if part == "*" => (0, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(part)?, number(part)?),
+        };
+        if first > last {
+            return None;
+        }
+        for n in first..=last {
+            bits |= 1 << n;
+        }
+    }
+    Some(bits)
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>, RelayError> {
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| RelayError::Parse(format!("schedule line {}: {}", i + 1, msg));
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 5 {
+            return Err(error("expected minute hour weekday channels action"));
+        }
+        let action = match fields[4..] {
+            ["on"] => Action::On,
+            ["off"] => Action::Off,
+            ["pulse", length] => {
+                Action::Pulse(duration(length).map_err(|e| error(&e.to_string()))?)
+            }
+            _ => return Err(error("expected on, off or pulse <duration>")),
+        };
+        entries.push(Entry {
+            minutes: field(fields[0], 59, &[]).ok_or_else(|| error("invalid minute"))?,
+            hours: field(fields[1], 23, &[]).ok_or_else(|| error("invalid hour"))? as u32,
+            weekdays: field(fields[2], 6, &WEEKDAYS).ok_or_else(|| error("invalid weekday"))? as u8,
+            mask: crate::channels(&[fields[3].to_string()]).map_err(|e| error(&e.to_string()))?,
+            action,
+        });
+    }
+    Ok(entries)
+}
+
+/// What to do for the entries due in a stretch of time.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan<'a> {
+    /// State of each channel left by missed `on` and `off` entries.
+    pub catch_up: BTreeMap<u8, bool>,
+    /// Entries that are on time, in order.
+    pub run: Vec<&'a Entry>,
+    /// Number of missed pulses that are dropped.
+    pub skipped: usize,
+}
+
+/// Plan the entries due after `last` up to and including `now`, applying the
+/// catch-up policy to the ones more than `GRACE` late.
+pub fn plan(entries: &[Entry], last: NaiveDateTime, now: NaiveDateTime) -> Plan<'_> {
+    let mut plan = Plan::default();
+    let lookback = Span::from_std(LOOKBACK).unwrap();
+    let grace = Span::from_std(GRACE).unwrap();
+    let mut at = last
+        .max(now - lookback)
+        .with_second(0)
+        .unwrap()
+        .with_nanosecond(0)
+        .unwrap();
+    at += Span::minutes(1);
+    while at <= now {
+        let late = now - at > grace;
+        for entry in entries.iter().filter(|entry| entry.matches(at)) {
+            match entry.action {
+                Action::On | Action::Off if late => {
+                    for channel in 1..=32u8 {
+                        if entry.mask & (1 << (channel - 1)) != 0 {
+                            plan.catch_up.insert(channel, entry.action == Action::On);
+                        }
+                    }
+                }
+                Action::Pulse(_) if late => plan.skipped += 1,
+                _ => plan.run.push(entry),
+            }
+        }
+        at += Span::minutes(1);
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2021-03-01 is a Monday.
+        NaiveDate::from_ymd(2021, 3, day).and_hms(hour, minute, 0)
+    }
+
+    const SCHEDULE: &str = "
+        # lab power
+        0    8  mon-fri 3   on
+        30   17 mon-fri 3   off  # evening
+        0,30 *  sat,sun 1-2 pulse 500ms
+    ";
+
+    #[test]
+    fn parse_entries() {
+        let entries = parse(SCHEDULE).unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!(Action::Pulse(Duration::from_millis(500)), entries[2].action);
+        assert_eq!(0b11, entries[2].mask);
+
+        assert!(entries[0].matches(at(1, 8, 0)));
+        assert!(!entries[0].matches(at(1, 8, 1)));
+        assert!(!entries[0].matches(at(6, 8, 0)));
+        assert!(entries[2].matches(at(7, 13, 30)));
+        assert!(!entries[2].matches(at(5, 13, 30)));
+
+        for bad in &[
+            "0 8 mon-fri 3",
+            "60 8 * 3 on",
+            "0 24 * 3 on",
+            "0 8 fri-mon 3 on",
+            "0 8 * 33 on",
+            "0 8 * 3 toggle",
+            "0 8 * 3 pulse soon",
+        ] {
+            assert_eq!(3, parse(bad).unwrap_err().exit_code(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(Duration::from_millis(250), duration("250ms").unwrap());
+        assert_eq!(Duration::from_secs(3), duration("3").unwrap());
+        assert_eq!(Duration::from_secs(120), duration("2m").unwrap());
+        assert!(duration("2h").is_err() && duration("ms").is_err());
+        assert!(duration(&format!("{}m", u64::MAX / 60 + 1)).is_err());
+    }
+
+    #[test]
+    fn catch_up_policy() {
+        let entries = parse(SCHEDULE).unwrap();
+
+        // On time: one minute at a time.
+        let due = plan(&entries, at(1, 7, 59), at(1, 8, 0));
+        assert_eq!(vec![&entries[0]], due.run);
+        assert!(due.catch_up.is_empty());
+
+        // Down from Friday noon to Monday 07:00: the Friday evening off is
+        // the last word on channel 3, the weekend pulses are dropped.
+        let due = plan(&entries, at(5, 12, 0), at(8, 7, 0));
+        assert!(due.run.is_empty());
+        assert_eq!(
+            vec![(3, false)],
+            due.catch_up.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(2 * 48, due.skipped);
+
+        // Started long after the last run, only LOOKBACK is considered.
+        let due = plan(&entries, at(1, 0, 0) - Span::weeks(10), at(3, 9, 0));
+        assert_eq!(
+            vec![(3, true)],
+            due.catch_up.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
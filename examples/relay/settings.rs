@@ -0,0 +1,189 @@
+//! Serial line settings of the relay tool, from `--<key> <value>` flags or a
+//! `--config` file of `key = value` lines. Flags win over the file.
+//!
+//! ```text
+//! # RS-485 adapter in the lab
+//! baud = 19200
+//! char-size = 8      # 5-8
+//! parity = none      # none, odd or even
+//! stop-bits = 1      # 1 or 2
+//! flow = none        # none, software or hardware
+//! timeout = 500ms
+//! ```
+
+use crate::error::RelayError;
+use crate::schedule;
+use serial::{BaudRate, CharSize, FlowControl, Parity, SerialPort, StopBits};
+use std::time::Duration;
+
+pub const KEYS: [&str; 6] = [
+    "baud",
+    "char-size",
+    "parity",
+    "stop-bits",
+    "flow",
+    "timeout",
+];
+
+/// Defaults to 9600-8N1 without flow control, the factory setting of the
+/// boards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub baud: BaudRate,
+    pub char_size: CharSize,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// How long to wait for a reply.
+    pub timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            baud: BaudRate::Baud9600,
+            char_size: CharSize::Bits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::Stop1,
+            flow_control: FlowControl::FlowNone,
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Settings {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), RelayError> {
+        let invalid = || RelayError::Parse(format!("invalid {} {:?}", key, value));
+        match key {
+            "baud" => {
+                let speed = value.parse::<usize>().map_err(|_| invalid())?;
+                if speed == 0 {
+                    return Err(invalid());
+                }
+                self.baud = BaudRate::from_speed(speed);
+            }
+            "char-size" => {
+                self.char_size = match value {
+                    "5" => CharSize::Bits5,
+                    "6" => CharSize::Bits6,
+                    "7" => CharSize::Bits7,
+                    "8" => CharSize::Bits8,
+                    _ => return Err(invalid()),
+                }
+            }
+            "parity" => {
+                self.parity = match value {
+                    "none" => Parity::ParityNone,
+                    "odd" => Parity::ParityOdd,
+                    "even" => Parity::ParityEven,
+                    _ => return Err(invalid()),
+                }
+            }
+            "stop-bits" => {
+                self.stop_bits = match value {
+                    "1" => StopBits::Stop1,
+                    "2" => StopBits::Stop2,
+                    _ => return Err(invalid()),
+                }
+            }
+            "flow" => {
+                self.flow_control = match value {
+                    "none" => FlowControl::FlowNone,
+                    "software" => FlowControl::FlowSoftware,
+                    "hardware" => FlowControl::FlowHardware,
+                    _ => return Err(invalid()),
+                }
+            }
+            "timeout" => self.timeout = schedule::duration(value).map_err(|_| invalid())?,
+            _ => return Err(RelayError::Usage(format!("unknown setting {}", key))),
+        }
+        Ok(())
+    }
+
+    /// Apply the `key = value` lines of a config file.
+    pub fn load(&mut self, text: &str) -> Result<(), RelayError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: String| RelayError::Parse(format!("config line {}: {}", i + 1, msg));
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected key = value".to_string()))?;
+            self.set(key.trim(), value.trim())
+                .map_err(|e| error(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply<P: SerialPort>(&self, port: &mut P) -> serial::Result<()> {
+        port.reconfigure(&|settings| {
+            settings.set_baud_rate(self.baud)?;
+            settings.set_char_size(self.char_size);
+            settings.set_parity(self.parity);
+            settings.set_stop_bits(self.stop_bits);
+            settings.set_flow_control(self.flow_control);
+            Ok(())
+        })?;
+        port.set_timeout(self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_and_override() {
+        let mut settings = Settings::default();
+        settings
+            .load(
+                "
+                # lab adapter
+                baud = 19200
+                parity=even   # checked
+                stop-bits = 2
+                timeout = 2s
+                ",
+            )
+            .unwrap();
+        settings.set("baud", "115200").unwrap();
+        assert_eq!(
+            Settings {
+                baud: BaudRate::Baud115200,
+                parity: Parity::ParityEven,
+                stop_bits: StopBits::Stop2,
+                timeout: Duration::from_secs(2),
+                ..Settings::default()
+            },
+            settings
+        );
+        settings.set("baud", "250000").unwrap();
+        assert_eq!(BaudRate::BaudOther(250000), settings.baud);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let mut settings = Settings::default();
+        for (key, value) in &[
+            ("baud", "fast"),
+            ("baud", "0"),
+            ("char-size", "9"),
+            ("parity", "mark"),
+            ("stop-bits", "1.5"),
+            ("flow", "xon"),
+            ("timeout", "soon"),
+        ] {
+            assert_eq!(
+                3,
+                settings.set(key, value).unwrap_err().exit_code(),
+                "{}",
+                key
+            );
+        }
+        assert_eq!(2, settings.set("speed", "9600").unwrap_err().exit_code());
+        assert!(settings.load("baud 9600").is_err());
+        assert_eq!(Settings::default(), settings);
+    }
+}
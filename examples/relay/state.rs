@@ -0,0 +1,195 @@
+//! The last commanded state of every board, kept in the file given with
+//! `--state` so `restore` can bring the channels back after a power cut.
+//!
+//! The file has one line per board, its address and channel mask:
+//!
+//! ```text
+//! # address mask, bit n - 1 is channel n
+//! 1 0x0000000f
+//! 2 0x80000000
+//! ```
+//!
+//! It is rewritten after every command that changes a channel, to a
+//! temporary file that is then renamed over the old one, so a crash never
+//! leaves a half written state behind.
+//!
+//! A schedule keeps the time it last ran in `<state file>.last-run` the same
+//! way, so a restart only catches up on the minutes it actually missed.
+
+use crate::error::RelayError;
+use crate::frame::{FUNC_FLIP_ONE, FUNC_OFF_ONE, FUNC_ON_ONE, FUNC_SET_MASK};
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    pub boards: BTreeMap<u8, u32>,
+}
+
+impl StateFile {
+    /// Load the state at `path`, starting empty if there is no file yet.
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<StateFile, RelayError> {
+        let path = path.as_ref().to_path_buf();
+        let boards = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(StateFile { path, boards })
+    }
+
+    /// Track a request sent to `address`. Returns true if it changed the
+    /// state.
+    pub fn record(&mut self, address: u8, function: u8, value: u32) -> bool {
+        let bit = match value {
+            channel @ 1..=32 => 1u32 << (channel - 1),
+            _ => 0,
+        };
+        let mask = self.boards.get(&address).copied().unwrap_or(0);
+        let new = match function {
+            FUNC_ON_ONE => mask | bit,
+            FUNC_OFF_ONE => mask & !bit,
+            FUNC_FLIP_ONE => mask ^ bit,
+            FUNC_SET_MASK => value,
+            _ => return false,
+        };
+        self.boards.insert(address, new) != Some(new)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut text = String::from("# address mask, bit n - 1 is channel n\n");
+        for (address, mask) in &self.boards {
+            text.push_str(&format!("{} {:#010x}\n", address, mask));
+        }
+        replace(&self.path, &text)
+    }
+}
+
+const LAST_RUN_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// The local time a schedule last ran, kept next to the state file.
+#[derive(Debug)]
+pub struct LastRun {
+    path: PathBuf,
+}
+
+impl LastRun {
+    pub fn beside<T: AsRef<Path>>(state: T) -> LastRun {
+        let mut path = state.as_ref().to_path_buf().into_os_string();
+        path.push(".last-run");
+        LastRun { path: path.into() }
+    }
+
+    /// The time saved last, if any.
+    pub fn load(&self) -> Result<Option<NaiveDateTime>, RelayError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        NaiveDateTime::parse_from_str(text.trim(), LAST_RUN_FORMAT)
+            .map(Some)
+            .map_err(|_| {
+                RelayError::Parse(format!(
+                    "{}: expected a time like 2021-03-01T08:00:00",
+                    self.path.display()
+                ))
+            })
+    }
+
+    pub fn save(&self, at: NaiveDateTime) -> io::Result<()> {
+        replace(&self.path, &format!("{}\n", at.format(LAST_RUN_FORMAT)))
+    }
+}
+
+/// Replace the file at `path` with `text` through a temporary file.
+fn replace(path: &Path, text: &str) -> io::Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+fn parse(text: &str) -> Result<BTreeMap<u8, u32>, RelayError> {
+    let mut boards = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let board = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [address, mask] => address
+                .parse::<u8>()
+                .ok()
+                .zip(u32::from_str_radix(mask.trim_start_matches("0x"), 16).ok()),
+            _ => None,
+        };
+        let (address, mask) = board.ok_or_else(|| {
+            RelayError::Parse(format!("state line {}: expected address and mask", i + 1))
+        })?;
+        boards.insert(address, mask);
+    }
+    Ok(boards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_save_open() {
+        let dir = std::env::temp_dir().join(format!("relay-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("relay.state");
+        let _ = fs::remove_file(&path);
+
+        let mut state = StateFile::open(&path).unwrap();
+        assert!(state.boards.is_empty());
+        assert!(state.record(1, FUNC_ON_ONE, 1));
+        assert!(!state.record(1, FUNC_ON_ONE, 1));
+        assert!(state.record(1, FUNC_FLIP_ONE, 4));
+        assert!(state.record(2, FUNC_SET_MASK, 0x8000_0000));
+        assert!(state.record(2, FUNC_OFF_ONE, 32));
+        assert!(!state.record(1, 0x10, 0));
+        state.save().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with("1 0x00000009\n2 0x00000000\n"), "{}", text);
+        let state = StateFile::open(&path).unwrap();
+        assert_eq!(
+            vec![(1, 9), (2, 0)],
+            state.boards.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+        fs::write(&path, "1 zz\n").unwrap();
+        assert_eq!(3, StateFile::open(&path).unwrap_err().exit_code());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_run() {
+        let dir = std::env::temp_dir().join(format!("relay-last-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let last_run = LastRun::beside(dir.join("relay.state"));
+        assert_eq!(None, last_run.load().unwrap());
+
+        let at = NaiveDateTime::parse_from_str("2021-03-01T08:05:00", LAST_RUN_FORMAT).unwrap();
+        last_run.save(at).unwrap();
+        assert_eq!(Some(at), last_run.load().unwrap());
+        assert_eq!(
+            "2021-03-01T08:05:00\n",
+            fs::read_to_string(dir.join("relay.state.last-run")).unwrap()
+        );
+
+        fs::write(dir.join("relay.state.last-run"), "yesterday\n").unwrap();
+        assert_eq!(3, last_run.load().unwrap_err().exit_code());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
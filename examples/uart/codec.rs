@@ -0,0 +1,242 @@
+//! Streaming decoder for the messages of the uart tool, a 4-byte big-endian
+//! length followed by that many bytes.
+//!
+//! Reads off a serial port stop wherever the timeout or the driver's buffer
+//! happens to fall, so the decoder keeps the bytes of an unfinished message
+//! until the rest arrives. A length over the limit can't be real: the decoder
+//! looks for a plausible length one byte further on, and reports each run of
+//! bytes it skipped that way once.
+//!
+//! The format has no checksum, so a corrupt length within the limit passes
+//! for a real one. The decoder then waits for that many bytes and hands them
+//! out as one message, and only lines up again if the bytes after it do.
+//! Keep the limit close to the largest real message to narrow the window.
+
+use std::fmt;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const HEADER_LEN: usize = 4;
+
+/// Default limit on the payload of a message.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// Bytes skipped while resynchronising, the first of them starting a
+    /// length prefix of `first_len`, over the limit.
+    Skipped { bytes: usize, first_len: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Skipped { bytes, first_len } => write!(
+                f,
+                "skipped {} bytes, from a frame length of {} over the limit",
+                bytes, first_len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Frame `payload` for the wire.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reassembles messages from the chunks pushed into it. As an iterator it
+/// yields the messages complete so far, then `None` until more bytes arrive.
+#[derive(Debug)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    // Bytes before this are consumed, they are dropped on the next push once
+    // they outweigh the rest.
+    start: usize,
+    max: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new(MAX_FRAME)
+    }
+}
+
+impl Decoder {
+    pub fn new(max: usize) -> Decoder {
+        Decoder {
+            buffer: Vec::new(),
+            start: 0,
+            max,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.start > self.pending() {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Read once from `reader` into the decoder, returning the number of
+    /// bytes read like `Read::read`.
+    pub fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0u8; 256];
+        let n = reader.read(&mut chunk)?;
+        self.push(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// `read_from` for async readers.
+    pub async fn read_from_async<R: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        reader: &mut R,
+    ) -> io::Result<usize> {
+        let mut chunk = [0u8; 256];
+        let n = reader.read(&mut chunk).await?;
+        self.push(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Bytes waiting for the rest of their message.
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+}
+
+impl Iterator for Decoder {
+    type Item = Result<Vec<u8>, FrameError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut skipped = 0;
+        let mut first_len = 0;
+        loop {
+            let bytes = &self.buffer[self.start..];
+            if bytes.len() < HEADER_LEN {
+                break;
+            }
+            let mut header = [0u8; HEADER_LEN];
+            header.copy_from_slice(&bytes[..HEADER_LEN]);
+            let len = u32::from_be_bytes(header) as usize;
+            if len > self.max {
+                if skipped == 0 {
+                    first_len = len;
+                }
+                skipped += 1;
+                self.start += 1;
+                continue;
+            }
+            // Report the run before the message it ends at.
+            if skipped > 0 {
+                break;
+            }
+            if bytes.len() < HEADER_LEN + len {
+                return None;
+            }
+            let message = bytes[HEADER_LEN..HEADER_LEN + len].to_vec();
+            self.start += HEADER_LEN + len;
+            return Some(Ok(message));
+        }
+        if skipped == 0 {
+            return None;
+        }
+        Some(Err(FrameError::Skipped {
+            bytes: skipped,
+            first_len,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_reads() {
+        let mut wire = encode(b"hello");
+        wire.extend(encode(b""));
+        wire.extend(encode(b"world"));
+        let mut decoder = Decoder::default();
+        let mut messages = vec![];
+        // One byte per read, the worst a port can do.
+        for byte in &wire {
+            decoder.push(&[*byte]);
+            messages.extend(decoder.by_ref().map(Result::unwrap));
+        }
+        assert_eq!(vec![b"hello".to_vec(), vec![], b"world".to_vec()], messages);
+        assert_eq!(0, decoder.pending());
+
+        decoder.push(&encode(b"later")[..6]);
+        assert_eq!(None, decoder.next());
+        assert_eq!(6, decoder.pending());
+    }
+
+    #[test]
+    fn resync_after_corrupt_length() {
+        let mut decoder = Decoder::new(8);
+        decoder.push(&[0xff, 0xff, 0xff]);
+        decoder.push(&encode(b"ok"));
+        decoder.push(&encode(&[0u8; 8]));
+        let messages = decoder.by_ref().collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Err(FrameError::Skipped {
+                    bytes: 3,
+                    first_len: 0xffff_ff00
+                }),
+                Ok(b"ok".to_vec()),
+                Ok(vec![0u8; 8]),
+            ],
+            messages
+        );
+
+        decoder.push(&encode(&[0u8; 9]));
+        assert!(matches!(
+            decoder.next(),
+            Some(Err(FrameError::Skipped {
+                bytes: 4,
+                first_len: 9
+            }))
+        ));
+    }
+
+    #[test]
+    fn long_garbage_burst() {
+        let mut decoder = Decoder::new(8);
+        decoder.push(&vec![0xff; 200_000]);
+        decoder.push(&encode(b"ok"));
+        assert_eq!(
+            vec![
+                Err(FrameError::Skipped {
+                    bytes: 200_000,
+                    first_len: 0xffff_ffff
+                }),
+                Ok(b"ok".to_vec()),
+            ],
+            decoder.by_ref().collect::<Vec<_>>()
+        );
+        assert_eq!(0, decoder.pending());
+
+        decoder.push(&encode(b"again"));
+        assert_eq!(Some(Ok(b"again".to_vec())), decoder.next());
+        assert!(decoder.buffer.len() < 200_000);
+    }
+
+    #[test]
+    fn read_from_reader() {
+        let wire = [encode(b"a"), encode(&[7u8; 300])].concat();
+        let mut reader = &wire[..];
+        let mut decoder = Decoder::default();
+        let mut messages = vec![];
+        while decoder.read_from(&mut reader).unwrap() > 0 {
+            messages.extend(decoder.by_ref().map(Result::unwrap));
+        }
+        assert_eq!(vec![b"a".to_vec(), vec![7u8; 300]], messages);
+    }
+}
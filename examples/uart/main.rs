@@ -0,0 +1,640 @@
+//! Talk to a device over a serial port with the length-prefixed messages of
+//! `codec`.
+//!
+//! A transfer ends with an empty message: `send` writes one after the file,
+//! and `receive` and `echo` stop when they get one, `echo` after sending it
+//! back. `device` and `call` speak the RPC protocol of `rpc` on top.
+//!
+//! `echo` runs on tokio, serving every port it is given from one thread.
+
+extern crate serial;
+
+#[path = "../common/async_serial.rs"]
+#[allow(dead_code)]
+mod async_serial;
+#[path = "../common/capture.rs"]
+#[allow(dead_code)]
+mod capture;
+#[allow(dead_code)]
+mod codec;
+#[allow(dead_code)]
+mod rpc;
+
+use async_serial::AsyncSerial;
+use capture::Capture;
+use codec::Decoder;
+use rpc::{Client, Device, RpcError};
+use serial::prelude::*;
+use serial::{BaudRate, CharSize, FlowControl, Parity, StopBits, SystemPort};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use std::{env, iter, process};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+const USAGE: &str = "Usage: uart [options] <port> <mode>
+Modes: send <file>     send the file in messages of up to 1024 bytes
+       receive <file>  write the payload of every message to the file, - for stdout
+       echo [port...]  send every message back, on each port given
+       dump            print every byte received as hex
+       device          act as a simulated device with the methods 0 ping,
+                       1 echo <bytes> and 2 add <u32> <u32>
+       call <method> [payload]  call a method, the payload and response in hex
+Options: --baud <n> (9600)  --framing <bits><parity><stop> (8N1)
+         --flow <none|software|hardware>  --timeout <ms> (1000)  --capture <file>";
+
+/// Largest payload `send` puts in one message.
+const CHUNK: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Send(String),
+    Receive(String),
+    /// The ports besides the first one.
+    Echo(Vec<String>),
+    Dump,
+    Device,
+    Call {
+        method: u8,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    port: String,
+    mode: Mode,
+    baud: BaudRate,
+    char_size: CharSize,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    timeout: Duration,
+    // File logging the traffic on the serial port.
+    capture: Option<String>,
+}
+
+impl Config {
+    fn new(args: Vec<String>) -> Result<Config, String> {
+        let mut config = Config {
+            port: String::new(),
+            mode: Mode::Dump,
+            baud: BaudRate::Baud9600,
+            char_size: CharSize::Bits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::Stop1,
+            flow_control: FlowControl::FlowNone,
+            timeout: Duration::from_millis(1000),
+            capture: None,
+        };
+        let mut positional = Vec::new();
+        let mut iter = args.into_iter().skip(1);
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for {}", arg))?;
+            let invalid = || format!("invalid value {:?} for {}", value, arg);
+            match arg.as_str() {
+                "--baud" => {
+                    let speed = value.parse::<usize>().map_err(|_| invalid())?;
+                    if speed == 0 {
+                        return Err(invalid());
+                    }
+                    config.baud = BaudRate::from_speed(speed);
+                }
+                "--framing" => {
+                    let framing = value.as_bytes();
+                    if framing.len() != 3 {
+                        return Err(invalid());
+                    }
+                    config.char_size = match framing[0] {
+                        b'5' => CharSize::Bits5,
+                        b'6' => CharSize::Bits6,
+                        b'7' => CharSize::Bits7,
+                        b'8' => CharSize::Bits8,
+                        _ => return Err(invalid()),
+                    };
+                    config.parity = match framing[1].to_ascii_uppercase() {
+                        b'N' => Parity::ParityNone,
+                        b'O' => Parity::ParityOdd,
+                        b'E' => Parity::ParityEven,
+                        _ => return Err(invalid()),
+                    };
+                    config.stop_bits = match framing[2] {
+                        b'1' => StopBits::Stop1,
+                        b'2' => StopBits::Stop2,
+                        _ => return Err(invalid()),
+                    };
+                }
+                "--flow" => {
+                    config.flow_control = match value.as_str() {
+                        "none" => FlowControl::FlowNone,
+                        "software" => FlowControl::FlowSoftware,
+                        "hardware" => FlowControl::FlowHardware,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "--timeout" => {
+                    config.timeout = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "--capture" => config.capture = Some(value),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        let mut positional = positional.into_iter();
+        config.port = positional.next().ok_or("missing port")?;
+        config.mode = match (positional.next().as_deref(), positional.next()) {
+            (Some("send"), Some(path)) => Mode::Send(path),
+            (Some("receive"), Some(path)) => Mode::Receive(path),
+            (Some("echo"), port) => Mode::Echo(port.into_iter().chain(&mut positional).collect()),
+            (Some("dump"), None) => Mode::Dump,
+            (Some("device"), None) => Mode::Device,
+            (Some("call"), Some(method)) => Mode::Call {
+                method: method
+                    .parse()
+                    .map_err(|_| format!("invalid method {}", method))?,
+                payload: hex(&positional.next().unwrap_or_default())?,
+            },
+            (Some(mode), _) => return Err(format!("invalid mode {}", mode)),
+            (None, _) => return Err("missing mode".to_string()),
+        };
+        if positional.next().is_some() {
+            return Err("too many arguments".to_string());
+        }
+        if let (Mode::Echo(others), Some(_)) = (&config.mode, &config.capture) {
+            if !others.is_empty() {
+                return Err("--capture takes a single port".to_string());
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn hex(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid hex {:?}", text);
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn configure<T: SerialPort>(port: &mut T, config: &Config) -> io::Result<()> {
+    port.reconfigure(&|settings| {
+        settings.set_baud_rate(config.baud)?;
+        settings.set_char_size(config.char_size);
+        settings.set_parity(config.parity);
+        settings.set_stop_bits(config.stop_bits);
+        settings.set_flow_control(config.flow_control);
+        Ok(())
+    })?;
+    port.set_timeout(config.timeout)?;
+    Ok(())
+}
+
+/// Hand every message arriving on `port` to `handle` until it returns false
+/// or the port is closed. Reads that time out are retried.
+fn messages<T: Read + Write>(
+    port: &mut T,
+    mut handle: impl FnMut(&mut T, Vec<u8>) -> io::Result<bool>,
+) -> io::Result<()> {
+    let mut decoder = Decoder::default();
+    loop {
+        match decoder.read_from(port) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+        for message in &mut decoder {
+            match message {
+                Ok(message) => {
+                    if !handle(port, message)? {
+                        return Ok(());
+                    }
+                }
+                Err(e) => eprintln!("uart: {}", e),
+            }
+        }
+    }
+}
+
+fn send<T: Write>(port: &mut T, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(CHUNK) {
+        port.write_all(&codec::encode(chunk))?;
+    }
+    port.write_all(&codec::encode(&[]))?;
+    port.flush()
+}
+
+fn receive<T: Read + Write, W: Write>(port: &mut T, out: &mut W) -> io::Result<()> {
+    messages(port, |_, message| {
+        out.write_all(&message)?;
+        Ok(!message.is_empty())
+    })?;
+    out.flush()
+}
+
+async fn echo<T: AsyncRead + AsyncWrite + Unpin>(mut port: T) -> io::Result<()> {
+    let mut decoder = Decoder::default();
+    loop {
+        if decoder.read_from_async(&mut port).await? == 0 {
+            return Ok(());
+        }
+        for message in &mut decoder {
+            match message {
+                Ok(message) => {
+                    port.write_all(&codec::encode(&message)).await?;
+                    if message.is_empty() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => eprintln!("uart: {}", e),
+            }
+        }
+    }
+}
+
+/// Echo on all `ports` at once, as tasks on a single-threaded runtime.
+fn echo_all<'a>(config: &Config, ports: impl Iterator<Item = &'a String>) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut tasks = vec![];
+        for path in ports {
+            let port = AsyncSerial::new(open(path, config)?)?;
+            tasks.push(match &config.capture {
+                Some(capture) => tokio::spawn(echo(Capture::create(port, capture)?)),
+                None => tokio::spawn(echo(port)),
+            });
+        }
+        for task in tasks {
+            task.await.map_err(io::Error::other)??;
+        }
+        Ok(())
+    })
+}
+
+/// A line of `dump`: offset, up to 16 bytes in hex and as ASCII.
+fn hex_line(offset: usize, bytes: &[u8]) -> String {
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let ascii = bytes
+        .iter()
+        .map(|&b| match b {
+            0x20..=0x7e => b as char,
+            _ => '.',
+        })
+        .collect::<String>();
+    format!("{:08x}  {:<47}  |{}|", offset, hex, ascii)
+}
+
+fn dump<T: Read, W: Write>(port: &mut T, out: &mut W) -> io::Result<()> {
+    let mut buf = [0u8; 256];
+    let mut offset = 0;
+    loop {
+        let n = match port.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        for line in buf[..n].chunks(16) {
+            writeln!(out, "{}", hex_line(offset, line))?;
+            offset += line.len();
+        }
+        out.flush()?;
+    }
+}
+
+fn transfer<T: Read + Write>(port: &mut T, config: &Config) -> io::Result<()> {
+    match &config.mode {
+        Mode::Send(path) => send(port, &fs::read(path)?),
+        Mode::Receive(path) if path == "-" => receive(port, &mut io::stdout()),
+        Mode::Receive(path) => receive(port, &mut File::create(path)?),
+        Mode::Echo(_) => unreachable!("echo runs async"),
+        Mode::Dump => dump(port, &mut io::stdout()),
+        Mode::Device => Device::new(port)
+            .method(0, |()| Ok(()))
+            .method(1, |bytes: Vec<u8>| Ok(bytes))
+            .method(2, |(a, b): (u32, u32)| {
+                a.checked_add(b).ok_or_else(|| "overflow".to_string())
+            })
+            .serve(),
+        Mode::Call { method, payload } => {
+            let mut client = Client::new(port);
+            client.timeout = config.timeout;
+            let response = client
+                .call_raw(*method, payload.clone())
+                .map_err(|e| match e {
+                    RpcError::Io(e) => e,
+                    e => io::Error::other(e.to_string()),
+                })?;
+            let response = response.iter().map(|b| format!("{:02x}", b));
+            println!("{}", response.collect::<String>());
+            Ok(())
+        }
+    }
+}
+
+fn open(path: &str, config: &Config) -> io::Result<SystemPort> {
+    let mut port = serial::open(path)?;
+    configure(&mut port, config)?;
+    Ok(port)
+}
+
+fn run(config: &Config) -> io::Result<()> {
+    if let Mode::Echo(others) = &config.mode {
+        return echo_all(config, iter::once(&config.port).chain(others));
+    }
+    let mut port = open(&config.port, config)?;
+    if let Mode::Call { .. } = config.mode {
+        // Reads must time out well within a call to retransmit in time.
+        port.set_timeout(config.timeout / 10)?;
+    }
+    match &config.capture {
+        Some(path) => transfer(&mut Capture::create(port, path)?, config),
+        None => transfer(&mut port, config),
+    }
+}
+
+fn main() {
+    let config = match Config::new(env::args().collect()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("uart: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&config) {
+        eprintln!("uart: {}: {}", config.port, e);
+        process::exit(1);
+    }
+}
+
+// The tests drive the tool over pseudo-terminals.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::thread;
+
+    fn config(args: &str) -> Result<Config, String> {
+        Config::new(args.split_whitespace().map(String::from).collect())
+    }
+
+    /// Open a pseudo-terminal in raw mode, returning its master side and the
+    /// path of the slave the tool opens like a serial port.
+    fn pty() -> (File, String) {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0, "posix_openpt: {}", io::Error::last_os_error());
+            assert_eq!(0, libc::grantpt(fd));
+            assert_eq!(0, libc::unlockpt(fd));
+            let mut name = [0 as libc::c_char; 64];
+            assert_eq!(0, libc::ptsname_r(fd, name.as_mut_ptr(), name.len()));
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            assert_eq!(0, libc::tcgetattr(fd, &mut termios));
+            libc::cfmakeraw(&mut termios);
+            assert_eq!(0, libc::tcsetattr(fd, libc::TCSANOW, &termios));
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_string();
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("uart-{}-{}", name, process::id()))
+    }
+
+    /// Run the tool in `mode` on the slave side of a new pty.
+    fn spawn(mode: &str) -> (File, thread::JoinHandle<io::Result<()>>) {
+        let (master, path) = pty();
+        let config = config(&format!(
+            "uart --baud 115200 --timeout 100 {} {}",
+            path, mode
+        ))
+        .unwrap();
+        let tool = thread::spawn(move || run(&config));
+        wait_configured(&master);
+        (master, tool)
+    }
+
+    /// Opening the port flushes its input, so wait until the tool has
+    /// configured it to 115200 baud before writing.
+    fn wait_configured(master: &File) {
+        let started = std::time::Instant::now();
+        while unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            libc::tcgetattr(master.as_raw_fd(), &mut termios);
+            libc::cfgetospeed(&termios) != libc::B115200
+        } {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "tool didn't start"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn read_messages(master: &mut File) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::default();
+        let mut messages = vec![];
+        loop {
+            decoder.read_from(master).unwrap();
+            for message in &mut decoder {
+                let message = message.unwrap();
+                if message.is_empty() {
+                    return messages;
+                }
+                messages.push(message);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let c = config("uart --baud 115200 --framing 7e2 /dev/ttyUSB0 send fw.bin").unwrap();
+        assert_eq!(
+            Config {
+                port: "/dev/ttyUSB0".to_string(),
+                mode: Mode::Send("fw.bin".to_string()),
+                baud: BaudRate::Baud115200,
+                char_size: CharSize::Bits7,
+                parity: Parity::ParityEven,
+                stop_bits: StopBits::Stop2,
+                ..config("uart /dev/ttyUSB0 dump").unwrap()
+            },
+            c
+        );
+        let c = config("uart --flow hardware --timeout 50 --capture wire.log COM3 echo").unwrap();
+        assert_eq!(FlowControl::FlowHardware, c.flow_control);
+        assert_eq!(Duration::from_millis(50), c.timeout);
+        assert_eq!(Some("wire.log".to_string()), c.capture);
+
+        let c = config("uart COM3 echo COM4 COM5").unwrap();
+        assert_eq!(Mode::Echo(vec!["COM4".into(), "COM5".into()]), c.mode);
+        assert!(config("uart --capture wire.log COM3 echo COM4").is_err());
+
+        for bad in &[
+            "uart",
+            "uart COM3",
+            "uart COM3 send",
+            "uart COM3 print",
+            "uart --baud 0 COM3 dump",
+            "uart --framing 9N1 COM3 dump",
+            "uart --framing 8X1 COM3 dump",
+            "uart --flow xon COM3 dump",
+            "uart --verbose 1 COM3 dump",
+            "uart COM3 dump --timeout",
+        ] {
+            assert!(config(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn call_config() {
+        let c = config("uart COM3 call 1 00026869").unwrap();
+        assert_eq!(
+            Mode::Call {
+                method: 1,
+                payload: vec![0, 2, b'h', b'i']
+            },
+            c.mode
+        );
+        let c = config("uart COM3 call 0").unwrap();
+        assert_eq!(
+            Mode::Call {
+                method: 0,
+                payload: vec![]
+            },
+            c.mode
+        );
+        for bad in &[
+            "uart COM3 call",
+            "uart COM3 call 256",
+            "uart COM3 call 1 abc",
+            "uart COM3 call 1 zz",
+        ] {
+            assert!(config(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn hex_lines() {
+        assert_eq!(
+            "00000010  55 01 12 41 7e 7f                                |U..A~.|",
+            hex_line(16, &[0x55, 0x01, 0x12, 0x41, 0x7e, 0x7f])
+        );
+        let full = hex_line(0, b"0123456789abcdef");
+        assert!(full.ends_with("66  |0123456789abcdef|"), "{}", full);
+    }
+
+    #[test]
+    fn send_over_pty() {
+        let file = temp("send");
+        let data = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(&file, &data).unwrap();
+        let (mut master, tool) = spawn(&format!("send {}", file.display()));
+        let messages = read_messages(&mut master);
+        tool.join().unwrap().unwrap();
+        assert_eq!(
+            vec![1024, 1024, 952],
+            messages.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(data, messages.concat());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn receive_over_pty() {
+        let file = temp("receive");
+        let (mut master, tool) = spawn(&format!("receive {}", file.display()));
+        for chunk in &[&b"hello "[..], b"over ", b"the pty", b""] {
+            master.write_all(&codec::encode(chunk)).unwrap();
+        }
+        tool.join().unwrap().unwrap();
+        assert_eq!(b"hello over the pty".to_vec(), fs::read(&file).unwrap());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn echo_over_pty() {
+        let (mut master, tool) = spawn("echo");
+        master.write_all(&codec::encode(b"ping")).unwrap();
+        master.write_all(&codec::encode(&[0xff; 300])).unwrap();
+        master.write_all(&codec::encode(b"")).unwrap();
+        assert_eq!(
+            vec![b"ping".to_vec(), vec![0xff; 300]],
+            read_messages(&mut master)
+        );
+        tool.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn device_over_pty() {
+        let (master, tool) = spawn("device");
+        let mut client = Client::new(master);
+        client.call::<_, ()>(0, &()).unwrap();
+        assert_eq!(
+            vec![1u8, 2, 3],
+            client.call::<_, Vec<u8>>(1, &vec![1u8, 2, 3]).unwrap()
+        );
+        assert_eq!(42u32, client.call(2, &(40u32, 2u32)).unwrap());
+        assert!(matches!(
+            client.call::<_, u32>(2, &(u32::MAX, 2u32)),
+            Err(RpcError::Remote(_))
+        ));
+        // Closing the master side hangs up the tool's port.
+        drop(client);
+        let _ = tool.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn echo_ports_concurrently() {
+        let (a, a_path) = pty();
+        let (b, b_path) = pty();
+        let config = config(&format!("uart --baud 115200 {} echo {}", a_path, b_path)).unwrap();
+        let tool = thread::spawn(move || run(&config));
+        wait_configured(&a);
+        wait_configured(&b);
+        let mut a = AsyncSerial::new(a).unwrap();
+        let mut b = AsyncSerial::new(b).unwrap();
+
+        async fn next(port: &mut AsyncSerial<File>, decoder: &mut Decoder) -> Vec<u8> {
+            loop {
+                if let Some(message) = decoder.next() {
+                    return message.unwrap();
+                }
+                assert!(decoder.read_from_async(port).await.unwrap() > 0);
+            }
+        }
+        let (mut from_a, mut from_b) = (Decoder::default(), Decoder::default());
+        // B answers while A's echo task is still waiting for its message.
+        b.write_all(&codec::encode(b"to b")).await.unwrap();
+        assert_eq!(b"to b".to_vec(), next(&mut b, &mut from_b).await);
+        a.write_all(&codec::encode(b"to a")).await.unwrap();
+        assert_eq!(b"to a".to_vec(), next(&mut a, &mut from_a).await);
+
+        // The empty message ends both tasks. Their last echo isn't read:
+        // once the tool closes the slave, the master may report the hangup
+        // before the bytes.
+        a.write_all(&codec::encode(b"")).await.unwrap();
+        b.write_all(&codec::encode(b"")).await.unwrap();
+        tool.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,598 @@
+//! Request/response calls over the length-prefixed messages of `codec`.
+//!
+//! Every message carries a header before its payload:
+//!
+//! ```text
+//! id (u16, big-endian) kind (u8) method (u8) payload...
+//! ```
+//!
+//! `kind` is 0 for a request, 1 for a response and 2 for an error, whose
+//! payload is a UTF-8 message. A response has the id and method of its
+//! request.
+//!
+//! A call that gets no response within its timeout is sent again with the
+//! same id, and the device answers a repeated request, the same id, method
+//! and payload, from its last response instead of running the method twice.
+//! Responses to other ids, like the late answer to a call that was already
+//! retried, are dropped. Clients start their ids at a time-based value, so a
+//! new client is unlikely to repeat the previous one's last request.
+
+use crate::codec::{self, Decoder};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const HEADER_LEN: usize = 4;
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// No response, even after retransmitting.
+    Timeout,
+    /// The device answered with an error.
+    Remote(String),
+    /// A value that doesn't fit the encoding.
+    Encode(String),
+    /// A payload that doesn't decode as the expected type.
+    Decode(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "timed out waiting for the device"),
+            RpcError::Remote(msg) => write!(f, "device error: {}", msg),
+            RpcError::Encode(msg) => write!(f, "can't encode: {}", msg),
+            RpcError::Decode(msg) => write!(f, "bad payload: {}", msg),
+            RpcError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<io::Error> for RpcError {
+    fn from(e: io::Error) -> RpcError {
+        RpcError::Io(e)
+    }
+}
+
+/// A payload type, encoded big-endian. Byte strings and strings carry a u16
+/// length so they can be combined in tuples, longer ones don't encode.
+pub trait Wire: Sized {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), RpcError>;
+
+    /// Decode from the front of `bytes`, advancing it past what was used.
+    fn decode(bytes: &mut &[u8]) -> Result<Self, RpcError>;
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], RpcError> {
+    if bytes.len() < n {
+        return Err(RpcError::Decode(format!(
+            "expected {} more bytes, got {}",
+            n,
+            bytes.len()
+        )));
+    }
+    let (head, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(head)
+}
+
+impl Wire for () {
+    fn encode(&self, _: &mut Vec<u8>) -> Result<(), RpcError> {
+        Ok(())
+    }
+
+    fn decode(_: &mut &[u8]) -> Result<(), RpcError> {
+        Ok(())
+    }
+}
+
+macro_rules! wire_int {
+    ($($int:ty),*) => {$(
+        impl Wire for $int {
+            fn encode(&self, out: &mut Vec<u8>) -> Result<(), RpcError> {
+                out.extend_from_slice(&self.to_be_bytes());
+                Ok(())
+            }
+
+            fn decode(bytes: &mut &[u8]) -> Result<$int, RpcError> {
+                let mut be = [0u8; std::mem::size_of::<$int>()];
+                be.copy_from_slice(take(bytes, std::mem::size_of::<$int>())?);
+                Ok(<$int>::from_be_bytes(be))
+            }
+        }
+    )*};
+}
+
+wire_int!(u8, u16, u32, i32);
+
+impl Wire for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), RpcError> {
+        if self.len() > u16::MAX as usize {
+            return Err(RpcError::Encode(format!(
+                "{} bytes over the limit of {}",
+                self.len(),
+                u16::MAX
+            )));
+        }
+        (self.len() as u16).encode(out)?;
+        out.extend_from_slice(self);
+        Ok(())
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Vec<u8>, RpcError> {
+        let len = u16::decode(bytes)? as usize;
+        Ok(take(bytes, len)?.to_vec())
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), RpcError> {
+        self.as_bytes().to_vec().encode(out)
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<String, RpcError> {
+        String::from_utf8(Vec::decode(bytes)?).map_err(|e| RpcError::Decode(e.to_string()))
+    }
+}
+
+impl<A: Wire, B: Wire> Wire for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), RpcError> {
+        self.0.encode(out)?;
+        self.1.encode(out)
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<(A, B), RpcError> {
+        Ok((A::decode(bytes)?, B::decode(bytes)?))
+    }
+}
+
+pub fn to_payload<T: Wire>(value: &T) -> Result<Vec<u8>, RpcError> {
+    let mut payload = vec![];
+    value.encode(&mut payload)?;
+    Ok(payload)
+}
+
+/// Decode a whole payload, which must hold nothing else.
+pub fn from_payload<T: Wire>(mut payload: &[u8]) -> Result<T, RpcError> {
+    let value = T::decode(&mut payload)?;
+    if !payload.is_empty() {
+        return Err(RpcError::Decode(format!(
+            "{} bytes left over",
+            payload.len()
+        )));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Request = 0,
+    Response = 1,
+    Error = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    id: u16,
+    kind: Kind,
+    method: u8,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.push(self.kind as u8);
+        bytes.push(self.method);
+        bytes.extend_from_slice(&self.payload);
+        codec::encode(&bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Message> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let kind = match bytes[2] {
+            0 => Kind::Request,
+            1 => Kind::Response,
+            2 => Kind::Error,
+            _ => return None,
+        };
+        Some(Message {
+            id: u16::from_be_bytes([bytes[0], bytes[1]]),
+            kind,
+            method: bytes[3],
+            payload: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// Read the next message off `port`, or `None` if none came in before the
+/// port's read timeout. Frames that don't hold a message are dropped.
+fn receive<T: Read>(port: &mut T, decoder: &mut Decoder) -> io::Result<Option<Message>> {
+    loop {
+        for frame in &mut *decoder {
+            if let Some(message) = frame.ok().as_deref().and_then(Message::decode) {
+                return Ok(Some(message));
+            }
+        }
+        match decoder.read_from(port) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Calls methods on the device at the other end of `port`. The port's read
+/// timeout should be well below the call timeout, which is only checked
+/// between reads.
+pub struct Client<T> {
+    port: T,
+    decoder: Decoder,
+    next_id: u16,
+    /// How long to wait for each response.
+    pub timeout: Duration,
+    /// How often to send a call again after a timeout.
+    pub retries: u32,
+}
+
+impl<T: Read + Write> Client<T> {
+    pub fn new(port: T) -> Client<T> {
+        Client {
+            port,
+            decoder: Decoder::default(),
+            next_id: first_id(),
+            timeout: Duration::from_secs(1),
+            retries: 2,
+        }
+    }
+
+    pub fn call<Req: Wire, Resp: Wire>(
+        &mut self,
+        method: u8,
+        request: &Req,
+    ) -> Result<Resp, RpcError> {
+        from_payload(&self.call_raw(method, to_payload(request)?)?)
+    }
+
+    /// Call `method` with an encoded payload, returning the encoded response.
+    pub fn call_raw(&mut self, method: u8, payload: Vec<u8>) -> Result<Vec<u8>, RpcError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let request = Message {
+            id,
+            kind: Kind::Request,
+            method,
+            payload,
+        }
+        .encode();
+        for _ in 0..=self.retries {
+            self.port.write_all(&request)?;
+            self.port.flush()?;
+            let deadline = Instant::now() + self.timeout;
+            while Instant::now() < deadline {
+                match receive(&mut self.port, &mut self.decoder)? {
+                    Some(reply) if reply.id == id && reply.kind == Kind::Response => {
+                        return Ok(reply.payload)
+                    }
+                    Some(reply) if reply.id == id && reply.kind == Kind::Error => {
+                        return Err(RpcError::Remote(
+                            String::from_utf8_lossy(&reply.payload).into_owned(),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(RpcError::Timeout)
+    }
+}
+
+/// Where a client starts numbering its calls, different for every process
+/// and run.
+fn first_id() -> u16 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos());
+    (nanos ^ process::id()) as u16
+}
+
+type Method = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>, String> + Send>;
+
+/// The device end: runs the methods registered on it for the requests coming
+/// in on `port`. Used to simulate a microcontroller.
+pub struct Device<T> {
+    port: T,
+    decoder: Decoder,
+    methods: HashMap<u8, Method>,
+    // The last request answered and the response, sent again if the client
+    // retransmits.
+    last: Option<(Message, Vec<u8>)>,
+}
+
+impl<T: Read + Write> Device<T> {
+    pub fn new(port: T) -> Device<T> {
+        Device {
+            port,
+            decoder: Decoder::default(),
+            methods: HashMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn method<Req, Resp, F>(mut self, method: u8, mut f: F) -> Device<T>
+    where
+        Req: Wire,
+        Resp: Wire,
+        F: FnMut(Req) -> Result<Resp, String> + Send + 'static,
+    {
+        let method_fn = move |payload: &[u8]| {
+            let request = from_payload(payload).map_err(|e| e.to_string())?;
+            let response = f(request)?;
+            to_payload(&response).map_err(|e| e.to_string())
+        };
+        self.methods.insert(method, Box::new(method_fn));
+        self
+    }
+
+    /// Answer requests until the port is closed.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let request = match receive(&mut self.port, &mut self.decoder) {
+                Ok(Some(message)) if message.kind == Kind::Request => message,
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let response = match &self.last {
+                Some((last, response)) if *last == request => response.clone(),
+                _ => {
+                    let response = self.handle(&request).encode();
+                    self.last = Some((request, response.clone()));
+                    response
+                }
+            };
+            self.port.write_all(&response)?;
+            self.port.flush()?;
+        }
+    }
+
+    fn handle(&mut self, request: &Message) -> Message {
+        let result = match self.methods.get_mut(&request.method) {
+            Some(method) => method(&request.payload),
+            None => Err(format!("unknown method {}", request.method)),
+        };
+        let (kind, payload) = match result {
+            Ok(payload) => (Kind::Response, payload),
+            Err(msg) => (Kind::Error, msg.into_bytes()),
+        };
+        Message {
+            id: request.id,
+            kind,
+            method: request.method,
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod duplex {
+    //! An in-memory serial line for tests.
+
+    use std::io::{self, Read, Write};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    pub struct End {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+        timeout: Duration,
+        /// Number of the next writes that are lost on the line.
+        pub drop_writes: Arc<AtomicU32>,
+    }
+
+    /// Two connected ends whose reads time out after `timeout`.
+    pub fn pair(timeout: Duration) -> (End, End) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let end = |tx, rx| End {
+            tx,
+            rx,
+            pending: vec![],
+            timeout,
+            drop_writes: Arc::new(AtomicU32::new(0)),
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    impl Read for End {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.rx.recv_timeout(self.timeout) {
+                    Ok(bytes) => self.pending = bytes,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for End {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let dropped = self
+                .drop_writes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if dropped.is_err() {
+                self.tx
+                    .send(buf.to_vec())
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const ADD: u8 = 1;
+    const GREET: u8 = 2;
+    const FAIL: u8 = 3;
+    const SLOW: u8 = 4;
+
+    /// A client talking to a simulated device, and how often the device ran
+    /// each method.
+    fn connect() -> (Client<duplex::End>, Arc<AtomicU32>, Arc<AtomicU32>) {
+        let (client, device) = duplex::pair(Duration::from_millis(5));
+        let runs = Arc::new(AtomicU32::new(0));
+        let drop_replies = device.drop_writes.clone();
+        let counter = runs.clone();
+        let mut device = Device::new(device)
+            .method(ADD, move |(a, b): (u32, u32)| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                a.checked_add(b).ok_or_else(|| "overflow".to_string())
+            })
+            .method(GREET, |name: String| Ok(format!("hello {}", name)))
+            .method(FAIL, |()| Err::<(), _>("broken".to_string()))
+            .method(SLOW, |ms: u32| {
+                thread::sleep(Duration::from_millis(ms as u64));
+                Ok(ms)
+            });
+        thread::spawn(move || device.serve());
+        let mut client = Client::new(client);
+        client.timeout = Duration::from_millis(50);
+        (client, runs, drop_replies)
+    }
+
+    #[test]
+    fn payloads() {
+        let value = (7u8, (String::from("relay"), vec![1u8, 2]));
+        let payload = to_payload(&value).unwrap();
+        assert_eq!(
+            vec![7, 0, 5, b'r', b'e', b'l', b'a', b'y', 0, 2, 1, 2],
+            payload
+        );
+        assert_eq!(value, from_payload(&payload).unwrap());
+        assert_eq!(-2i32, from_payload(&to_payload(&-2i32).unwrap()).unwrap());
+        assert_eq!(65537, to_payload(&vec![0u8; 65535]).unwrap().len());
+        assert!(matches!(
+            to_payload(&(1u8, vec![0u8; 65536])),
+            Err(RpcError::Encode(_))
+        ));
+        assert!(matches!(
+            from_payload::<u32>(&[1, 2]),
+            Err(RpcError::Decode(_))
+        ));
+        assert!(matches!(
+            from_payload::<u8>(&[1, 2]),
+            Err(RpcError::Decode(_))
+        ));
+        assert!(from_payload::<String>(&[0, 1, 0xff]).is_err());
+    }
+
+    #[test]
+    fn calls() {
+        let (mut client, _, _) = connect();
+        assert_eq!(5u32, client.call(ADD, &(2u32, 3u32)).unwrap());
+        assert_eq!(
+            "hello board".to_string(),
+            client
+                .call::<_, String>(GREET, &"board".to_string())
+                .unwrap()
+        );
+        for (method, expected) in &[
+            (FAIL, "broken"),
+            (9, "unknown method 9"),
+            (GREET, "bad payload: expected 2 more bytes, got 0"),
+        ] {
+            match client.call::<_, ()>(*method, &()) {
+                Err(RpcError::Remote(msg)) => assert_eq!(*expected, msg),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!(matches!(
+            client.call::<_, u32>(ADD, &(u32::MAX, 1u32)),
+            Err(RpcError::Remote(_))
+        ));
+        // The wrong response type is caught on this side.
+        assert!(matches!(
+            client.call::<_, u8>(ADD, &(1u32, 1u32)),
+            Err(RpcError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn retransmission() {
+        let (mut client, runs, drop_replies) = connect();
+
+        // The request is lost: it is sent again and runs once.
+        client.port.drop_writes.store(1, Ordering::SeqCst);
+        assert_eq!(3u32, client.call(ADD, &(1u32, 2u32)).unwrap());
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+
+        // The response is lost: the device answers the repeat from its last
+        // response without adding again.
+        drop_replies.store(1, Ordering::SeqCst);
+        assert_eq!(7u32, client.call(ADD, &(3u32, 4u32)).unwrap());
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+
+        // A slow method is answered twice, the second answer arrives during
+        // the next call and is dropped there.
+        assert_eq!(80u32, client.call(SLOW, &80u32).unwrap());
+        assert_eq!(2u32, client.call(ADD, &(1u32, 1u32)).unwrap());
+
+        // Nothing gets through.
+        client.port.drop_writes.store(3, Ordering::SeqCst);
+        assert!(matches!(
+            client.call::<_, u32>(ADD, &(1u32, 1u32)),
+            Err(RpcError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn separate_clients() {
+        let (mut first, runs, _) = connect();
+        let id = first.next_id;
+        assert_eq!(42u32, first.call(ADD, &(40u32, 2u32)).unwrap());
+
+        // A second client on the same line, say the next `uart call`, that
+        // happens to start at the same id is not answered from the cache.
+        let mut second = Client::new(first.port);
+        second.timeout = Duration::from_millis(50);
+        second.next_id = id;
+        assert_eq!(2u32, second.call(ADD, &(1u32, 1u32)).unwrap());
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+
+        assert!(matches!(
+            second.call::<_, Vec<u8>>(GREET, &vec![0u8; 70000]),
+            Err(RpcError::Encode(_))
+        ));
+    }
+}